@@ -2,10 +2,12 @@ use chardet;
 use encoding;
 use encoding::label::encoding_from_whatwg_label;
 use encoding::EncodingRef;
-use encoding::{DecoderTrap, EncoderTrap};
+use encoding::{DecoderTrap, EncoderTrap, RawDecoder};
 use ropey;
-use ropey::Rope;
+use ropey::{Rope, RopeBuilder, RopeSlice};
+use std::borrow::Cow;
 use std::fmt;
+use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io;
@@ -13,25 +15,480 @@ use std::io::prelude::Write;
 use std::io::Read;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::SystemTime;
+use unicode_segmentation::{GraphemeCursor, GraphemeIncomplete};
+
+use crate::editorconfig::{EditorConfig, IndentStyle};
+use crate::SETTINGS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indentation {
+    Tab(u32),
+    Space(u32),
+}
+
+impl Indentation {
+    /// number of columns of one indentation level
+    pub fn width(&self) -> usize {
+        match *self {
+            Indentation::Tab(n) | Indentation::Space(n) => n as usize,
+        }
+    }
+
+    /// return the whitespace string filling the given number of columns
+    pub fn whitespace(&self, cols: usize) -> String {
+        match *self {
+            Indentation::Tab(n) => {
+                // a zero width tab would never fill anything
+                let n = std::cmp::max(n as usize, 1);
+                let mut s = "\t".repeat(cols / n);
+                s.push_str(&" ".repeat(cols % n));
+                s
+            }
+            Indentation::Space(_) => " ".repeat(cols),
+        }
+    }
+
+    /// return the string of one indentation level
+    pub fn unit(&self) -> String {
+        self.whitespace(self.width())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineFeed {
+    CR,
+    LF,
+    CRLF,
+}
+
+impl LineFeed {
+    /// return the chars of the line ending
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            LineFeed::CR => "\r",
+            LineFeed::LF => "\n",
+            LineFeed::CRLF => "\r\n",
+        }
+    }
+}
+
+impl Default for LineFeed {
+    fn default() -> Self {
+        #[cfg(target_os = "windows")]
+        let linefeed = LineFeed::CRLF;
+        #[cfg(not(target_os = "windows"))]
+        let linefeed = LineFeed::LF;
+        linefeed
+    }
+}
+
+impl fmt::Display for LineFeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            LineFeed::CR => write!(f, "CR"),
+            LineFeed::LF => write!(f, "LF"),
+            LineFeed::CRLF => write!(f, "CRLF"),
+        }
+    }
+}
+
+impl fmt::Display for Indentation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Indentation::Tab(n) => write!(f, "Tab Size: {}", n),
+            Indentation::Space(n) => write!(f, "Spaces: {}", n),
+        }
+    }
+}
+
+/// return the encoding with the given name or whatwg label
+pub fn encoding_from_label(label: &str) -> Option<EncodingRef> {
+    let label = label.trim().to_lowercase();
+    encoding::all::encodings()
+        .iter()
+        .cloned()
+        .find(|e| e.name() == label)
+        .or_else(|| encoding_from_whatwg_label(&label))
+}
+
+/// return the byte order mark of the given encoding, if it has one
+fn bom_of(encoding: EncodingRef) -> Option<&'static [u8]> {
+    match encoding.name() {
+        "utf-8" => Some(&[0xEF, 0xBB, 0xBF]),
+        "utf-16le" => Some(&[0xFF, 0xFE]),
+        "utf-16be" => Some(&[0xFE, 0xFF]),
+        _ => None,
+    }
+}
+
+/// detect the byte order mark at the begining of the data
+/// return the encoding it stands for and its length in bytes
+fn detect_bom(data: &[u8]) -> Option<(EncodingRef, usize)> {
+    if data.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        Some((encoding::all::UTF_8, 3))
+    } else if data.starts_with(&[0xFF, 0xFE]) {
+        Some((encoding::all::UTF_16LE, 2))
+    } else if data.starts_with(&[0xFE, 0xFF]) {
+        Some((encoding::all::UTF_16BE, 2))
+    } else {
+        None
+    }
+}
+
+/// add the file name to an io error
+fn with_context(e: io::Error, action: &str, filename: &Path) -> io::Error {
+    io::Error::new(e.kind(), format!("Not saved: can't {} {}: {}", action, filename.display(), e))
+}
+
+/// write the data to a temporary file next to the target, then rename it over the target
+/// so that the file is never left half written. Permissions of the existing file are kept.
+/// If `backup` is set, the previous content is copied to `filename.bak` first.
+pub(crate) fn write_atomic(filename: &Path, data: &[u8], backup: bool) -> io::Result<()> {
+    // write through symlinks instead of replacing them
+    let target = fs::canonicalize(filename).unwrap_or_else(|_| filename.to_owned());
+    let dir = match target.parent() {
+        Some(dir) if dir != Path::new("") => dir.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    let name = target
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let tmp = dir.join(format!(".{}.{}.tmp", name, std::process::id()));
+
+    // renaming would replace a file which can't be written
+    if let Err(e) = OpenOptions::new().write(true).open(&target) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(with_context(e, "write", &target));
+        }
+    }
+
+    let result = (|| {
+        let mut file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&tmp)
+            .map_err(|e| with_context(e, "create temporary file", &tmp))?;
+        file.write_all(data)
+            .map_err(|e| with_context(e, "write temporary file", &tmp))?;
+        file.sync_all()
+            .map_err(|e| with_context(e, "flush temporary file", &tmp))?;
+
+        if let Ok(metadata) = fs::metadata(&target) {
+            fs::set_permissions(&tmp, metadata.permissions())
+                .map_err(|e| with_context(e, "set the permissions of", &tmp))?;
+            if backup {
+                let mut bak = target.clone().into_os_string();
+                bak.push(".bak");
+                fs::copy(&target, &bak).map_err(|e| with_context(e, "backup", &target))?;
+            }
+        }
+        fs::rename(&tmp, &target).map_err(|e| with_context(e, "replace", &target))?;
+        sync_dir(&dir).map_err(|e| with_context(e, "flush the directory", &dir))
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// flush a directory, so that a file renamed in it is still there after a crash
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// directories can't be opened as files on Windows, where renames are journaled by the file system
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+static REVISION: AtomicUsize = AtomicUsize::new(0);
+
+/// return a revision number never used before
+fn next_revision() -> usize {
+    REVISION.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+/// What is known of the file on disk, to detect modifications made by other programs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: u64,
+}
+
+impl FileStamp {
+    fn new(filename: &Path, data: &[u8]) -> Self {
+        let metadata = fs::metadata(filename).ok();
+        FileStamp {
+            modified: metadata.as_ref().and_then(|m| m.modified().ok()),
+            len: data.len() as u64,
+            hash: hash_bytes(data),
+        }
+    }
+}
+
+/// choose the encoding of data starting with `sample`, return it with the length of its byte order mark
+/// The requested encoding wins, then a byte order mark gives the charset for sure,
+/// then the default one, then detection.
+fn choose_encoding(sample: &[u8], encoding: Option<EncodingRef>, default: Option<EncodingRef>) -> (EncodingRef, Option<usize>) {
+    let bom = match (detect_bom(sample), encoding) {
+        (Some((coder, _)), Some(encoding)) if coder.name() != encoding.name() => None,
+        (bom, _) => bom,
+    };
+    let coder = match (encoding, bom, default) {
+        (Some(coder), _, _) => coder,
+        (None, Some((coder, _)), _) => coder,
+        (None, None, Some(coder)) => coder,
+        (None, None, None) => {
+            let content = &sample[bom.map(|(_, len)| len).unwrap_or(0)..];
+            let result = chardet::detect(content);
+            let encoding = chardet::charset2encoding(&result.0);
+            encoding_from_whatwg_label(encoding).unwrap_or(encoding::all::UTF_8)
+        }
+    };
+    (coder, bom.map(|(_, len)| len))
+}
+
+/// Size of the sample used to detect the encoding and the settings of large files
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Size of the chunks read and decoded at once when loading large files
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// return true if the file is big enough to be opened in large-file mode
+pub fn is_large_file(filename: &Path) -> bool {
+    let threshold = SETTINGS.read().unwrap().get::<u64>("largeFileThreshold").unwrap();
+    fs::metadata(filename).map(|m| m.len() > threshold).unwrap_or(false)
+}
+
+/// What a background loader reports
+pub enum LoadEvent {
+    /// number of bytes loaded and total size of the file
+    Progress(u64, u64),
+    Done(io::Result<LoadedFile>),
+}
+
+/// The content of a file decoded in the background
+pub struct LoadedFile {
+    rope: Rope,
+    encoding: EncodingRef,
+    has_bom: bool,
+    stamp: FileStamp,
+    // the encoding was requested instead of detected
+    requested: bool,
+}
+
+/// decode the input into the output, replacing invalid sequences
+/// Incomplete sequences at the end are kept by the decoder for the next chunk.
+fn decode_chunk(decoder: &mut dyn RawDecoder, input: &[u8], output: &mut String) {
+    let mut remaining = 0;
+    while remaining < input.len() {
+        match decoder.raw_feed(&input[remaining..], output) {
+            (_, Some(err)) => {
+                output.push('\u{FFFD}');
+                remaining = (remaining as isize + err.upto) as usize;
+            }
+            (_, None) => remaining = input.len(),
+        }
+    }
+}
+
+/// read and decode a file by chunks, reporting the progress
+fn load_chunks(
+    filename: &Path,
+    encoding: Option<EncodingRef>,
+    default: Option<EncodingRef>,
+    events: &mpsc::Sender<LoadEvent>,
+) -> io::Result<LoadedFile> {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+
+    let mut file = File::open(filename)?;
+    let total = file.metadata()?.len();
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut len = 0;
+    while len < SAMPLE_SIZE {
+        match file.read(&mut chunk[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    let (coder, bom) = choose_encoding(&chunk[..std::cmp::min(len, SAMPLE_SIZE)], encoding, default);
+
+    let mut decoder = coder.raw_decoder();
+    let mut builder = RopeBuilder::new();
+    let mut hasher = DefaultHasher::new();
+    let mut text = String::new();
+    let mut loaded = len as u64;
+    hasher.write(&chunk[..len]);
+    decode_chunk(&mut *decoder, &chunk[bom.unwrap_or(0)..len], &mut text);
+    loop {
+        builder.append(&text);
+        text.clear();
+        // the receiver is gone if the view was closed meanwhile
+        if events.send(LoadEvent::Progress(loaded, total)).is_err() {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "Loading cancelled"));
+        }
+        let n = file.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        hasher.write(&chunk[..n]);
+        loaded += n as u64;
+        decode_chunk(&mut *decoder, &chunk[..n], &mut text);
+    }
+    if decoder.raw_finish(&mut text).is_some() {
+        text.push('\u{FFFD}');
+    }
+    builder.append(&text);
+
+    let metadata = fs::metadata(filename)?;
+    Ok(LoadedFile {
+        rope: builder.finish(),
+        encoding: coder,
+        has_bom: bom.is_some(),
+        stamp: FileStamp {
+            modified: metadata.modified().ok(),
+            len: loaded,
+            hash: hasher.finish(),
+        },
+        requested: encoding.is_some(),
+    })
+}
+
+/// load a file by chunks in a background thread, return the receiver of its events
+fn spawn_loader(filename: &Path, encoding: Option<EncodingRef>, default: Option<EncodingRef>) -> mpsc::Receiver<LoadEvent> {
+    let (sender, receiver) = mpsc::channel();
+    let filename = filename.to_owned();
+    thread::spawn(move || {
+        let result = load_chunks(&filename, encoding, default, &sender);
+        let _ = sender.send(LoadEvent::Done(result));
+    });
+    receiver
+}
+
+/// return true if the file can be opened for writing
+fn is_writable(filename: &Path) -> bool {
+    OpenOptions::new().write(true).open(filename).is_ok()
+}
+
+pub(crate) fn hash_bytes(data: &[u8]) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+    let mut hasher = DefaultHasher::new();
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// State of the file on disk compared to when it was loaded or saved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskState {
+    Unchanged,
+    Modified,
+    Deleted,
+}
+
+/// Iterator over the grapheme clusters of a rope slice
+/// A cluster is borrowed from the rope, unless it spans two of its chunks.
+pub struct Graphemes<'a> {
+    text: RopeSlice<'a>,
+    chunks: ropey::iter::Chunks<'a>,
+    chunk: &'a str,
+    chunk_start: usize,
+    cursor: GraphemeCursor,
+}
+
+impl<'a> Graphemes<'a> {
+    fn new(text: RopeSlice<'a>) -> Self {
+        let mut chunks = text.chunks();
+        let chunk = chunks.next().unwrap_or("");
+        Graphemes {
+            text,
+            chunks,
+            chunk,
+            chunk_start: 0,
+            cursor: GraphemeCursor::new(0, text.len_bytes(), true),
+        }
+    }
+}
+
+impl<'a> Iterator for Graphemes<'a> {
+    type Item = Cow<'a, str>;
+
+    fn next(&mut self) -> Option<Cow<'a, str>> {
+        let start = self.cursor.cur_cursor();
+        let end = loop {
+            match self.cursor.next_boundary(self.chunk, self.chunk_start) {
+                Ok(None) => return None,
+                Ok(Some(end)) => break end,
+                Err(GraphemeIncomplete::NextChunk) => {
+                    self.chunk_start += self.chunk.len();
+                    self.chunk = self.chunks.next().unwrap_or("");
+                }
+                Err(GraphemeIncomplete::PreContext(end)) => provide_context(&mut self.cursor, self.text, end),
+                Err(_) => unreachable!(),
+            }
+        };
+        if start < self.chunk_start {
+            let (start, end) = (self.text.byte_to_char(start), self.text.byte_to_char(end));
+            Some(Cow::Owned(self.text.slice(start..end).to_string()))
+        } else {
+            Some(Cow::Borrowed(&self.chunk[start - self.chunk_start..end - self.chunk_start]))
+        }
+    }
+}
+
+/// give the grapheme cursor the text before `end` it needs to find a boundary
+fn provide_context(cursor: &mut GraphemeCursor, text: RopeSlice<'_>, end: usize) {
+    let (chunk, start, _, _) = text.chunk_at_byte(end - 1);
+    cursor.provide_context(&chunk[..end - start], start);
+}
 
 /// A text Buffer
 #[derive(Clone)]
 pub struct Buffer {
     rope: Rope,
     filename: Option<PathBuf>,
-    is_dirty: bool,
+    stamp: Option<FileStamp>,
+    revision: usize,
+    saved_revision: usize,
     encoding: EncodingRef,
+    has_bom: bool,
+    indentation: Indentation,
+    tab_size: usize,
+    linefeed: LineFeed,
+    mixed_linefeed: bool,
+    trim_trailing_whitespace: bool,
+    insert_final_newline: bool,
+    read_only: bool,
+    large: bool,
+    // a large file whose content is still being read in the background
+    loading: bool,
+    // name of the syntax chosen by the user, overriding the detected one
+    syntax: Option<String>,
 }
 
 impl fmt::Debug for Buffer {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Buffer {{rope: {:?}, filename: {:?}, is_dirty: {}, encoding: {} }}",
+            "Buffer {{rope: {:?}, filename: {:?}, revision: {}, saved_revision: {}, encoding: {}, has_bom: {}, indentation: {:?}, linefeed: {:?} }}",
             self.rope,
             self.filename,
-            self.is_dirty,
-            self.encoding.name()
+            self.revision,
+            self.saved_revision,
+            self.encoding.name(),
+            self.has_bom,
+            self.indentation,
+            self.linefeed
         )
     }
 }
@@ -39,46 +496,377 @@ impl fmt::Debug for Buffer {
 impl Buffer {
     /// Create a new empty buffer
     pub fn new() -> Self {
-        Buffer {
+        let tab_size: usize = SETTINGS.read().unwrap().get("tabSize").unwrap();
+        let revision = next_revision();
+        let mut b = Buffer {
             rope: Rope::new(),
             filename: None,
-            is_dirty: false,
+            stamp: None,
+            revision,
+            saved_revision: revision,
             encoding: encoding_from_whatwg_label("utf8").unwrap(),
-        }
+            has_bom: false,
+            indentation: Indentation::Space(tab_size as u32),
+            tab_size,
+            linefeed: LineFeed::default(),
+            mixed_linefeed: false,
+            trim_trailing_whitespace: false,
+            insert_final_newline: false,
+            read_only: false,
+            large: false,
+            loading: false,
+            syntax: None,
+        };
+        b.indentation = b.detect_indentation();
+        b
     }
     /// create a buffer from the given string
     pub fn from_str(text: &str) -> Self {
-        Buffer {
-            rope: Rope::from_str(text),
-            filename: None,
-            is_dirty: false,
-            encoding: encoding_from_whatwg_label("utf8").unwrap(),
-        }
+        let mut b = Buffer::new();
+        b.rope = Rope::from_str(text);
+        b.detect_settings();
+        b
     }
     /// create a buffer from the give file
+    /// The `.editorconfig` files applying to it override the detected settings
     pub fn from_file(filename: &Path) -> Result<Self, io::Error> {
+        Buffer::from_file_with_encoding(filename, None)
+    }
+
+    /// create a buffer from the give file, decoded with the given encoding instead of the detected one
+    pub fn from_file_with_encoding(filename: &Path, encoding: Option<EncodingRef>) -> Result<Self, io::Error> {
         let mut fh = io::BufReader::new(File::open(filename)?);
         let mut reader: Vec<u8> = Vec::new();
 
         // read file
         fh.read_to_end(&mut reader)?;
 
-        // detect charset of the file
-        let result = chardet::detect(&reader);
+        let config = EditorConfig::from_path(filename);
+        let mut b = Buffer::decode(&reader, encoding, config.encoding());
+        b.filename = Some(filename.to_owned());
+        b.stamp = Some(FileStamp::new(filename, &reader));
+        b.read_only = !is_writable(filename);
+        b.detect_settings();
+        b.apply_file_editorconfig(&config, encoding.is_some());
+        Ok(b)
+    }
 
-        // decode file into utf-8
-        let encoding = chardet::charset2encoding(&result.0);
-        println!("Detected Encoding: {}", encoding);
-        let coder = encoding_from_whatwg_label(encoding).unwrap_or(encoding::all::UTF_8);
-        let utf8reader = coder.decode(&reader, DecoderTrap::Replace).expect("Error");
+    /// create an empty buffer for a large file, and load it in a background thread
+    /// The buffer can't be modified nor saved until `finish_loading` is called with the loaded file.
+    pub fn load_large_file(filename: &Path, encoding: Option<EncodingRef>) -> (Self, mpsc::Receiver<LoadEvent>) {
+        let config = EditorConfig::from_path(filename);
+        let mut b = Buffer::new();
+        b.filename = Some(filename.to_owned());
+        b.large = true;
+        b.loading = true;
+        b.apply_editorconfig(&config);
+        let events = spawn_loader(filename, encoding, config.encoding());
+        (b, events)
+    }
 
-        let r = Rope::from_str(&utf8reader);
-        Ok(Buffer {
-            rope: r,
-            filename: Some(filename.to_owned()),
-            is_dirty: false,
-            encoding: coder,
-        })
+    /// read a large file again in a background thread, with the current encoding
+    /// The buffer is loading until `finish_loading` is called with the loaded file.
+    pub fn reload_in_background(&mut self) -> io::Result<mpsc::Receiver<LoadEvent>> {
+        let filename = match &self.filename {
+            Some(f) => f.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::Other, "No filename associated")),
+        };
+        self.loading = true;
+        Ok(spawn_loader(&filename, Some(self.encoding), None))
+    }
+
+    /// set the content of a large file loaded in the background
+    /// The read-only state chosen meanwhile is kept, a file which can't be written makes it read-only.
+    pub fn finish_loading(&mut self, file: LoadedFile) {
+        self.rope = file.rope;
+        self.encoding = file.encoding;
+        self.has_bom = file.has_bom;
+        self.stamp = Some(file.stamp);
+        self.revision = next_revision();
+        self.saved_revision = self.revision;
+        self.loading = false;
+        self.detect_settings();
+        if let Some(filename) = self.filename.clone() {
+            self.read_only |= !is_writable(&filename);
+            self.apply_file_editorconfig(&EditorConfig::from_path(&filename), file.requested);
+        }
+    }
+
+    /// give up loading after an error
+    /// A buffer reloaded in the background keeps its previous content, one never loaded stays blocked.
+    pub fn abort_loading(&mut self) {
+        if self.stamp.is_some() {
+            self.loading = false;
+        }
+    }
+
+    /// return true if the content of the file is still being read in the background
+    pub fn is_loading(&self) -> bool {
+        self.loading
+    }
+
+    /// return an error if the buffer is still loading, as its content is not the one of the file yet
+    fn check_loaded(&self) -> io::Result<()> {
+        if self.loading {
+            let name = self.filename.as_ref().map(|f| f.display().to_string()).unwrap_or_default();
+            return Err(io::Error::new(io::ErrorKind::Other, format!("Not saved: {} is still loading", name)));
+        }
+        Ok(())
+    }
+
+    /// return the number of chars or lines looked at to detect the settings
+    fn sample_len(&self) -> usize {
+        if self.large {
+            SAMPLE_SIZE
+        } else {
+            usize::max_value()
+        }
+    }
+
+    /// return true if the buffer is a large file, for which the expensive features are disabled
+    pub fn is_large(&self) -> bool {
+        self.large
+    }
+
+    /// create an empty buffer for a file which doesn't exist yet, it is created on save
+    pub fn new_file(filename: &Path) -> Self {
+        let mut b = Buffer::new();
+        b.filename = Some(filename.to_owned());
+        b.apply_editorconfig(&EditorConfig::from_path(filename));
+        b
+    }
+
+    /// create a buffer without file from raw bytes, decoded with the given encoding or the detected one
+    pub fn from_bytes(data: &[u8], encoding: Option<EncodingRef>) -> Self {
+        let mut b = Buffer::decode(data, encoding, None);
+        b.detect_settings();
+        b
+    }
+
+    /// decode the content of a buffer
+    fn decode(data: &[u8], encoding: Option<EncodingRef>, default: Option<EncodingRef>) -> Self {
+        let (coder, bom) = choose_encoding(data, encoding, default);
+        let content = &data[bom.unwrap_or(0)..];
+
+        // decode into utf-8
+        let utf8reader = coder.decode(content, DecoderTrap::Replace).expect("Error");
+
+        let mut b = Buffer::new();
+        b.rope = Rope::from_str(&utf8reader);
+        b.encoding = coder;
+        b.has_bom = bom.is_some();
+        b
+    }
+
+    /// apply the editorconfig properties to the buffer settings
+    pub fn apply_editorconfig(&mut self, config: &EditorConfig) {
+        if let Some(tab_width) = config.tab_width() {
+            self.set_tab_size(tab_width);
+        }
+        let width = config.indent_width().unwrap_or_else(|| self.indentation.width()) as u32;
+        match config.indent_style {
+            Some(IndentStyle::Tab) => self.set_indentation(Indentation::Tab(self.tab_size as u32)),
+            Some(IndentStyle::Space) => self.set_indentation(Indentation::Space(width)),
+            None => {
+                if let Indentation::Space(_) = self.indentation {
+                    self.set_indentation(Indentation::Space(width));
+                }
+            }
+        }
+        if let Some(linefeed) = config.end_of_line {
+            self.linefeed = linefeed;
+        }
+        if let Some(encoding) = config.encoding() {
+            self.encoding = encoding;
+        }
+        match config.charset.as_ref().map(|c| c.as_str()) {
+            Some("utf-8-bom") => self.has_bom = true,
+            Some("utf-8") | Some("latin1") => self.has_bom = false,
+            _ => (),
+        }
+        if let Some(trim) = config.trim_trailing_whitespace {
+            self.trim_trailing_whitespace = trim;
+        }
+        if let Some(final_newline) = config.insert_final_newline {
+            self.insert_final_newline = final_newline;
+        }
+    }
+
+    /// apply the editorconfig properties to a decoded file
+    /// The charset doesn't override an encoding requested explicitly.
+    fn apply_file_editorconfig(&mut self, config: &EditorConfig, requested_encoding: bool) {
+        let (encoding, has_bom) = (self.encoding, self.has_bom);
+        self.apply_editorconfig(config);
+        if requested_encoding {
+            self.encoding = encoding;
+            self.has_bom = has_bom;
+        }
+    }
+
+    /// detect the indentation and the line ending of the buffer content
+    pub fn detect_settings(&mut self) {
+        self.linefeed = self.detect_linefeed();
+        self.mixed_linefeed = self.has_mixed_linefeed();
+        self.set_indentation(self.detect_indentation());
+    }
+
+    /// return the indentation used by the buffer
+    pub fn get_indentation(&self) -> Indentation {
+        self.indentation
+    }
+
+    /// set the indentation used by the buffer
+    /// Tab indentation also set the displayed width of tabulations
+    pub fn set_indentation(&mut self, indentation: Indentation) {
+        self.indentation = indentation;
+        if let Indentation::Tab(n) = indentation {
+            self.tab_size = n as usize;
+        }
+    }
+
+    /// return the displayed width of a tabulation
+    pub fn get_tab_size(&self) -> usize {
+        self.tab_size
+    }
+
+    /// set the displayed width of a tabulation
+    pub fn set_tab_size(&mut self, tab_size: usize) {
+        self.tab_size = std::cmp::max(tab_size, 1);
+        if let Indentation::Tab(_) = self.indentation {
+            self.indentation = Indentation::Tab(self.tab_size as u32);
+        }
+    }
+
+    /// return the line ending inserted in the buffer
+    pub fn get_linefeed(&self) -> LineFeed {
+        self.linefeed
+    }
+
+    /// set the line ending inserted in the buffer
+    pub fn set_linefeed(&mut self, linefeed: LineFeed) {
+        self.linefeed = linefeed;
+    }
+
+    /// return true if the buffer contained several kinds of line ending when last detected or converted
+    pub fn is_linefeed_mixed(&self) -> bool {
+        self.mixed_linefeed
+    }
+
+    /// count the line endings of the buffer, as (CR, LF, CRLF)
+    fn count_linefeeds(&self) -> (usize, usize, usize) {
+        let mut cr = 0;
+        let mut lf = 0;
+        let mut crlf = 0;
+
+        let mut chars = self.chars().take(self.sample_len()).peekable();
+        while let Some(c) = chars.next() {
+            if c == '\r' {
+                if chars.peek() == Some(&'\n') {
+                    chars.next();
+                    crlf += 1;
+                } else {
+                    cr += 1;
+                }
+            } else if c == '\n' {
+                lf += 1;
+            }
+        }
+        (cr, lf, crlf)
+    }
+
+    /// return true if the buffer contains several kinds of line ending
+    pub fn has_mixed_linefeed(&self) -> bool {
+        let (cr, lf, crlf) = self.count_linefeeds();
+        [cr, lf, crlf].iter().filter(|n| **n > 0).count() > 1
+    }
+
+    /// Detect the carriage return type of the buffer
+    /// The most used line ending of the whole buffer wins
+    pub fn detect_linefeed(&self) -> LineFeed {
+        let (cr, lf, crlf) = self.count_linefeeds();
+
+        if cr > crlf && cr > lf {
+            LineFeed::CR
+        } else if lf > crlf && lf > cr {
+            LineFeed::LF
+        } else if crlf == 0 {
+            LineFeed::default()
+        } else {
+            LineFeed::CRLF
+        }
+    }
+
+    /// replace every line ending of the buffer with the given one, and use it for new lines
+    pub fn convert_linefeed(&mut self, linefeed: LineFeed) {
+        let mut text = String::with_capacity(self.len_chars());
+        let mut changed = false;
+        let mut chars = self.chars().peekable();
+        while let Some(c) = chars.next() {
+            let current = match c {
+                '\r' if chars.peek() == Some(&'\n') => {
+                    chars.next();
+                    LineFeed::CRLF
+                }
+                '\r' => LineFeed::CR,
+                '\n' => LineFeed::LF,
+                _ => {
+                    text.push(c);
+                    continue;
+                }
+            };
+            changed |= current != linefeed;
+            text.push_str(linefeed.as_str());
+        }
+        if changed {
+            self.rope = Rope::from_str(&text);
+            self.touch();
+        }
+        self.linefeed = linefeed;
+        self.mixed_linefeed = false;
+    }
+
+    /// Detect the indentation of the buffer
+    /// Fallback to the `indentWithSpace` and `tabSize` settings when nothing is indented
+    pub fn detect_indentation(&self) -> Indentation {
+        let tabsize: u32 = SETTINGS.read().unwrap().get("tabSize").unwrap();
+
+        // detect Tabs first. If the first char of a line is more often a Tab
+        // then we consider the indentation as tabulation.
+        let mut tab = 0;
+        let mut space = 0;
+        for line in self.lines().take(self.sample_len()) {
+            match line.chars().next() {
+                Some(' ') => space += 1,
+                Some('\t') => tab += 1,
+                _ => (),
+            }
+        }
+        if tab > space {
+            return Indentation::Tab(tabsize);
+        }
+
+        // Algorythm from
+        // https://medium.com/firefox-developer-tools/detecting-code-indentation-eff3ed0fb56b
+        use std::collections::HashMap;
+        let mut indents = HashMap::new();
+        let mut last = 0;
+
+        for line in self.lines().take(self.sample_len()) {
+            let width = line.chars().take_while(|c| *c == ' ').count();
+            let indent = (width as isize - last as isize).abs();
+            if indent > 1 {
+                let i = indents.entry(indent).or_insert(0);
+                *i += 1;
+            }
+            last = width;
+        }
+        if let Some(i) = indents.iter().max_by(|x, y| x.1.cmp(y.1)) {
+            Indentation::Space(*i.0 as u32)
+        } else if SETTINGS.read().unwrap().get("indentWithSpace").unwrap() {
+            Indentation::Space(tabsize)
+        } else {
+            Indentation::Tab(tabsize)
+        }
     }
 
     /// return the buffer current encoding
@@ -86,6 +874,225 @@ impl Buffer {
         self.encoding
     }
 
+    /// set the encoding used when saving the buffer
+    /// The byte order mark is dropped if the new encoding doesn't have one
+    pub fn set_encoding(&mut self, encoding: EncodingRef) {
+        self.encoding = encoding;
+        if bom_of(encoding).is_none() {
+            self.has_bom = false;
+        }
+    }
+
+    /// return true if a byte order mark is written at the begining of the file
+    pub fn has_bom(&self) -> bool {
+        self.has_bom
+    }
+
+    /// add or remove the byte order mark written at the begining of the file
+    pub fn set_bom(&mut self, has_bom: bool) -> io::Result<()> {
+        if has_bom && bom_of(self.encoding).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} has no byte order mark", self.encoding.name()),
+            ));
+        }
+        if self.has_bom != has_bom {
+            self.has_bom = has_bom;
+            self.touch();
+        }
+        Ok(())
+    }
+
+    /// read the file again, decoding it with the given encoding
+    pub fn reload_with_encoding(&mut self, encoding: EncodingRef) -> io::Result<()> {
+        if self.loading {
+            return Err(io::Error::new(io::ErrorKind::Other, "The file is still loading"));
+        }
+        let filename = match &self.filename {
+            Some(f) => f.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::Other, "No filename associated")),
+        };
+        let mut reader: Vec<u8> = Vec::new();
+        io::BufReader::new(File::open(&filename)?).read_to_end(&mut reader)?;
+
+        let bom = detect_bom(&reader).filter(|(bom_encoding, _)| bom_encoding.name() == encoding.name());
+        let text = encoding
+            .decode(&reader[bom.map(|(_, len)| len).unwrap_or(0)..], DecoderTrap::Replace)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.into_owned()))?;
+        self.rope = Rope::from_str(&text);
+        self.encoding = encoding;
+        self.has_bom = bom.is_some();
+        self.stamp = Some(FileStamp::new(&filename, &reader));
+        self.revision = next_revision();
+        self.saved_revision = self.revision;
+        self.linefeed = self.detect_linefeed();
+        self.mixed_linefeed = self.has_mixed_linefeed();
+        Ok(())
+    }
+
+    /// return the position (line, column) and the first char that can't be represented in the buffer encoding
+    pub fn find_unencodable_char(&self) -> Option<(usize, usize, char)> {
+        let mut line = 0;
+        let mut col = 0;
+        let mut tmp = [0u8; 4];
+        for c in self.chars() {
+            if self.encoding.encode(c.encode_utf8(&mut tmp), EncoderTrap::Strict).is_err() {
+                return Some((line, col, c));
+            }
+            if c == '\n' {
+                line += 1;
+                col = 0;
+            } else {
+                col += 1;
+            }
+        }
+        None
+    }
+
+    /// return true if the buffer was modified since it was loaded or saved
+    pub fn is_dirty(&self) -> bool {
+        self.revision != self.saved_revision
+    }
+
+    /// return true if the buffer must not be modified
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// forbid or allow the modifications of the buffer
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// return the name of the syntax chosen by the user, if any
+    pub fn get_syntax(&self) -> Option<&str> {
+        self.syntax.as_ref().map(|s| s.as_str())
+    }
+
+    /// override the detected syntax, None to detect it again
+    pub fn set_syntax(&mut self, syntax: Option<String>) {
+        self.syntax = syntax;
+    }
+
+    /// return a number identifying the current content of the buffer
+    pub fn get_revision(&self) -> usize {
+        self.revision
+    }
+
+    /// mark the buffer as modified
+    fn touch(&mut self) {
+        self.revision = next_revision();
+    }
+
+    /// replace the content and settings by the ones of a previous state of the buffer,
+    /// keeping the file association, the known state of the file on disk and the saved revision
+    pub fn restore(&mut self, mut previous: Buffer) {
+        previous.filename = self.filename.take();
+        previous.stamp = self.stamp;
+        previous.saved_revision = self.saved_revision;
+        previous.read_only = self.read_only;
+        previous.syntax = self.syntax.take();
+        *self = previous;
+    }
+
+    /// compare with another version of the buffer
+    /// Return the lines of this buffer which differ, and the number of lines of `other` replacing them.
+    pub fn changed_lines(&self, other: &Buffer) -> (Range<usize>, usize) {
+        let (len, other_len) = (self.len_lines(), other.len_lines());
+        let common = len.min(other_len);
+        let mut start = 0;
+        while start < common && self.line(start) == other.line(start) {
+            start += 1;
+        }
+        let mut end = 0;
+        while end < common - start && self.line(len - 1 - end) == other.line(other_len - 1 - end) {
+            end += 1;
+        }
+        (start..len - end, other_len - end - start)
+    }
+
+    /// compare the file on disk with the one loaded or saved
+    /// Large files are only compared by modification time and length.
+    pub fn check_disk(&mut self) -> DiskState {
+        if self.loading {
+            return DiskState::Unchanged;
+        }
+        let filename = match &self.filename {
+            Some(f) => f,
+            None => return DiskState::Unchanged,
+        };
+        let stamp = match self.stamp {
+            Some(stamp) => stamp,
+            // a file saved under a new name is not known yet
+            None => return DiskState::Unchanged,
+        };
+        let metadata = match fs::metadata(filename) {
+            Ok(m) => m,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return DiskState::Deleted,
+            Err(_) => return DiskState::Unchanged,
+        };
+        if metadata.len() == stamp.len && metadata.modified().ok() == stamp.modified {
+            return DiskState::Unchanged;
+        }
+        if self.large {
+            return DiskState::Modified;
+        }
+        // the modification time changed, check the content to ignore a simple touch
+        match fs::read(filename) {
+            Ok(data) => {
+                let current = FileStamp::new(filename, &data);
+                if current.hash == stamp.hash {
+                    self.stamp = Some(current);
+                    DiskState::Unchanged
+                } else {
+                    DiskState::Modified
+                }
+            }
+            Err(_) => DiskState::Unchanged,
+        }
+    }
+
+    /// forget the file on disk, after it has been deleted
+    /// The buffer is marked as modified, as its content is not saved anywhere anymore.
+    pub fn forget_disk(&mut self) {
+        self.stamp = None;
+        self.touch();
+    }
+
+    /// consider the current file on disk as the known one, so that it can be overwritten
+    pub fn accept_disk(&mut self) {
+        if let Some(filename) = &self.filename {
+            self.stamp = if self.large {
+                fs::metadata(filename).ok().map(|m| FileStamp {
+                    modified: m.modified().ok(),
+                    len: m.len(),
+                    hash: 0,
+                })
+            } else {
+                fs::read(filename).ok().map(|data| FileStamp::new(filename, &data))
+            };
+        }
+    }
+
+    /// return the content of the file on disk, decoded with the buffer encoding
+    pub fn read_disk(&self) -> io::Result<String> {
+        let filename = match &self.filename {
+            Some(f) => f,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "No filename associated")),
+        };
+        let data = fs::read(filename)?;
+        let bom = detect_bom(&data).filter(|(bom_encoding, _)| bom_encoding.name() == self.encoding.name());
+        self.encoding
+            .decode(&data[bom.map(|(_, len)| len).unwrap_or(0)..], DecoderTrap::Replace)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.into_owned()))
+    }
+
+    /// read the file again with the current encoding
+    pub fn reload(&mut self) -> io::Result<()> {
+        let encoding = self.encoding;
+        self.reload_with_encoding(encoding)
+    }
+
     /// return the filename
     pub fn get_filename(&self) -> Option<&Path> {
         match &self.filename {
@@ -94,27 +1101,108 @@ impl Buffer {
         }
     }
 
+    /// remove the whitespaces at the end of every line
+    pub fn trim_trailing_whitespace(&mut self) {
+        for line in (0..self.len_lines()).rev() {
+            let end = self.line_to_last_char(line);
+            let start = self.line_to_char(line);
+            let mut trimmed = end;
+            while trimmed > start {
+                match self.rope.char(trimmed - 1) {
+                    ' ' | '\t' => trimmed -= 1,
+                    _ => break,
+                }
+            }
+            if trimmed < end {
+                self.remove(trimmed..end);
+            }
+        }
+    }
+
+    /// add a line ending at the end of the buffer if there is none
+    pub fn ensure_final_newline(&mut self) {
+        let len = self.len_chars();
+        if len > 0 {
+            match self.rope.char(len - 1) {
+                '\n' | '\r' => (),
+                _ => {
+                    let linefeed = self.linefeed;
+                    self.insert(len, linefeed.as_str());
+                }
+            }
+        }
+    }
+
     /// save the current buffer to disk
+    /// Trailing whitespaces and final newline are handled according to the editorconfig properties,
+    /// on a copy of the buffer which replaces it only once written.
     pub fn save(&mut self) -> io::Result<()> {
-        if let Some(filename) = &self.filename {
-            if let Ok(r) = self.encoding.encode(&self.rope.to_string(), EncoderTrap::Replace) {
-                let mut file = OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .truncate(true)
-                    .open(filename)?;
-                file.write_all(&r)?;
-                Ok(())
-            } else {
-                return Err(io::Error::new(io::ErrorKind::Other, "Error while encoding buffer"));
+        self.check_loaded()?;
+        if self.read_only {
+            let name = self.filename.as_ref().map(|f| f.display().to_string()).unwrap_or_default();
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Not saved: {} is read-only", name),
+            ));
+        }
+        if self.check_disk() == DiskState::Modified {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "Not saved: {} was modified by another program",
+                    self.filename.as_ref().unwrap().display()
+                ),
+            ));
+        }
+        let filename = match &self.filename {
+            Some(f) => f.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::Other, "No filename associated")),
+        };
+        let mut saved = self.clone();
+        if saved.trim_trailing_whitespace {
+            saved.trim_trailing_whitespace();
+        }
+        if saved.insert_final_newline {
+            saved.ensure_final_newline();
+        }
+        if SETTINGS.read().unwrap().get::<bool>("normalizeLineEndings").unwrap() {
+            let linefeed = saved.linefeed;
+            saved.convert_linefeed(linefeed);
+        }
+        if let Ok(mut r) = saved.encoding.encode(&saved.rope.to_string(), EncoderTrap::Strict) {
+            if saved.has_bom {
+                if let Some(bom) = bom_of(saved.encoding) {
+                    r.splice(0..0, bom.iter().cloned());
+                }
             }
+            let backup = SETTINGS.read().unwrap().get::<bool>("backupOnSave").unwrap();
+            write_atomic(&filename, &r, backup)?;
+            saved.stamp = Some(FileStamp::new(&filename, &r));
+            saved.saved_revision = saved.revision;
+            *self = saved;
+            Ok(())
         } else {
-            return Err(io::Error::new(io::ErrorKind::Other, "No filename associated"));
+            let message = match saved.find_unencodable_char() {
+                Some((line, col, c)) => format!(
+                    "Not saved: {:?} at line {}, column {} can't be represented in {}",
+                    c,
+                    line + 1,
+                    col + 1,
+                    saved.encoding.name()
+                ),
+                None => format!("Not saved: error while encoding buffer in {}", saved.encoding.name()),
+            };
+            Err(io::Error::new(io::ErrorKind::InvalidData, message))
         }
     }
 
     /// save the current buffer to disk with the given filename
+    /// A read-only buffer becomes writable when saved to another file.
     pub fn save_as<P: AsRef<Path>>(&mut self, filename: P) -> io::Result<()> {
+        self.check_loaded()?;
+        if self.filename.as_ref().map(|f| f.as_path()) != Some(filename.as_ref()) {
+            self.read_only = false;
+        }
         self.set_filename(filename.as_ref());
         self.save()?;
         Ok(())
@@ -122,6 +1210,9 @@ impl Buffer {
 
     /// set filename
     pub fn set_filename(&mut self, filename: &Path) {
+        if self.filename.as_ref().map(|f| f.as_path()) != Some(filename) {
+            self.stamp = None;
+        }
         self.filename = Some(filename.to_owned());
     }
 
@@ -133,11 +1224,67 @@ impl Buffer {
         self.rope.lines()
     }
 
+    /// return the given line, with its line ending
+    pub fn line(&self, line_idx: usize) -> ropey::RopeSlice<'_> {
+        self.rope.line(line_idx)
+    }
+
+    /// return a copy of the text, cheap as it shares the nodes of the rope
+    pub fn snapshot(&self) -> Rope {
+        self.rope.clone()
+    }
+
     /// iterate over chars of the given line
     pub fn chars_on_line(&self,line: usize) -> ropey::iter::Chars<'_> {
         self.rope.line(line).chars()
     }
 
+    /// iterate over the grapheme clusters of the given line, with its line ending
+    pub fn graphemes_on_line(&self, line: usize) -> Graphemes<'_> {
+        Graphemes::new(self.rope.line(line))
+    }
+
+    /// return the start of the grapheme cluster before the given char
+    pub fn prev_grapheme_boundary(&self, char_idx: usize) -> usize {
+        let text = self.rope.slice(..);
+        let byte_idx = self.rope.char_to_byte(char_idx);
+        let (mut chunk, mut chunk_start, _, _) = self.rope.chunk_at_byte(byte_idx);
+        let mut cursor = GraphemeCursor::new(byte_idx, self.rope.len_bytes(), true);
+        loop {
+            match cursor.prev_boundary(chunk, chunk_start) {
+                Ok(None) => return 0,
+                Ok(Some(n)) => return self.rope.byte_to_char(n),
+                Err(GraphemeIncomplete::PrevChunk) => {
+                    let (c, start, _, _) = self.rope.chunk_at_byte(chunk_start - 1);
+                    chunk = c;
+                    chunk_start = start;
+                }
+                Err(GraphemeIncomplete::PreContext(n)) => provide_context(&mut cursor, text, n),
+                Err(_) => unreachable!(),
+            }
+        }
+    }
+
+    /// return the end of the grapheme cluster starting at the given char
+    pub fn next_grapheme_boundary(&self, char_idx: usize) -> usize {
+        let text = self.rope.slice(..);
+        let byte_idx = self.rope.char_to_byte(char_idx);
+        let (mut chunk, mut chunk_start, _, _) = self.rope.chunk_at_byte(byte_idx);
+        let mut cursor = GraphemeCursor::new(byte_idx, self.rope.len_bytes(), true);
+        loop {
+            match cursor.next_boundary(chunk, chunk_start) {
+                Ok(None) => return self.rope.len_chars(),
+                Ok(Some(n)) => return self.rope.byte_to_char(n),
+                Err(GraphemeIncomplete::NextChunk) => {
+                    chunk_start += chunk.len();
+                    chunk = self.rope.chunk_at_byte(chunk_start).0;
+                }
+                Err(GraphemeIncomplete::PreContext(n)) => provide_context(&mut cursor, text, n),
+                Err(_) => unreachable!(),
+            }
+        }
+    }
+
     /// Total number of chars in the buffer
     pub fn len_chars(&self) -> usize {
         self.rope.len_chars()
@@ -149,17 +1296,17 @@ impl Buffer {
     /// insert ch at the given position
     pub fn insert_char(&mut self, char_idx: usize, ch: char) {
         self.rope.insert_char(char_idx, ch);
-        self.is_dirty = true;
+        self.touch();
     }
     /// Insert the string at the given position
     pub fn insert<S: AsRef<str>>(&mut self, char_idx: usize, text: S) {
         self.rope.insert(char_idx, text.as_ref());
-        self.is_dirty = true;
+        self.touch();
     }
     /// remove the given range from the buffer
     pub fn remove<R: Into<Range<usize>>>(&mut self, char_range: R) {
         self.rope.remove(char_range.into());
-        self.is_dirty = true;
+        self.touch();
     }
 
     /// Returns the entire buffer as a newly allocated String.
@@ -211,7 +1358,35 @@ impl Buffer {
 
 #[cfg(test)]
 mod tests {
-    use crate::buffer::Buffer;
+    use crate::buffer::{Buffer, DiskState, Indentation, LineFeed, LoadEvent, CHUNK_SIZE};
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A directory for the files of a test, removed with its content when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        /// create a directory unique to this process and test
+        fn new() -> Self {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let n = COUNT.fetch_add(1, Ordering::SeqCst);
+            let dir = std::env::temp_dir().join(format!("nonedit-test-{}-{}", std::process::id(), n));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        /// return the path of a file of the directory
+        fn file(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
 
     #[test]
     fn chars_iterators() {
@@ -232,6 +1407,33 @@ mod tests {
         assert_eq!(buf.len_chars(), 4);
     }
     #[test]
+    fn whitespace() {
+        assert_eq!(Indentation::Tab(4).whitespace(10), "\t\t  ");
+        assert_eq!(Indentation::Space(4).whitespace(3), "   ");
+        assert_eq!(Indentation::Tab(0).whitespace(2), "\t\t");
+    }
+    #[test]
+    fn graphemes() {
+        // clusters spanning the chunks of the rope
+        let text = "e\u{301}\u{1F469}\u{200D}\u{1F4BB}\r\n".repeat(1000);
+        let buf = Buffer::from_str(&text.replace("\r\n", ""));
+        let clusters: Vec<String> = buf.graphemes_on_line(0).map(|g| g.into_owned()).collect();
+        assert_eq!(clusters.len(), 2000);
+        assert!(clusters.iter().step_by(2).all(|g| g == "e\u{301}"));
+        assert!(clusters.iter().skip(1).step_by(2).all(|g| g == "\u{1F469}\u{200D}\u{1F4BB}"));
+
+        let buf = Buffer::from_str(&text);
+        let mut idx = 0;
+        let mut lens = Vec::new();
+        while idx < buf.len_chars() {
+            let next = buf.next_grapheme_boundary(idx);
+            assert_eq!(buf.prev_grapheme_boundary(next), idx);
+            lens.push(next - idx);
+            idx = next;
+        }
+        assert_eq!(lens, [2, 3, 2].repeat(1000));
+    }
+    #[test]
     fn len_lines() {
         let buf = Buffer::from_str("Hello World");
         assert_eq!(buf.len_lines(), 1);
@@ -280,4 +1482,186 @@ mod tests {
         assert_eq!(buf.line_len_no_eol(1), 5);
         assert_eq!(buf.line_len_no_eol(2), 6);
     }
+    #[test]
+    fn trim_trailing_whitespace() {
+        let mut buf = Buffer::from_str("text  \n\tplops\t\r\n  \ntoto ");
+        buf.trim_trailing_whitespace();
+        assert_eq!(buf.to_string(), "text\n\tplops\r\n\ntoto");
+    }
+    #[test]
+    fn ensure_final_newline() {
+        let mut buf = Buffer::from_str("text\r\nplops");
+        buf.set_linefeed(LineFeed::CRLF);
+        buf.ensure_final_newline();
+        assert_eq!(buf.to_string(), "text\r\nplops\r\n");
+        buf.ensure_final_newline();
+        assert_eq!(buf.to_string(), "text\r\nplops\r\n");
+    }
+    #[test]
+    fn detect_linefeed() {
+        let buf = Buffer::from_str("text\r\nplops\r\ntoto\n");
+        assert_eq!(buf.get_linefeed(), LineFeed::CRLF);
+        assert!(buf.is_linefeed_mixed());
+        let buf = Buffer::from_str("text\nplops\n");
+        assert_eq!(buf.get_linefeed(), LineFeed::LF);
+        assert!(!buf.is_linefeed_mixed());
+    }
+    #[test]
+    fn convert_linefeed() {
+        let mut buf = Buffer::from_str("text\r\nplops\rtoto\n");
+        buf.convert_linefeed(LineFeed::LF);
+        assert_eq!(buf.to_string(), "text\nplops\ntoto\n");
+        buf.convert_linefeed(LineFeed::CRLF);
+        assert_eq!(buf.to_string(), "text\r\nplops\r\ntoto\r\n");
+        assert_eq!(buf.get_linefeed(), LineFeed::CRLF);
+        assert!(!buf.is_linefeed_mixed());
+    }
+    #[test]
+    fn encoding_from_label() {
+        assert_eq!(super::encoding_from_label("iso-8859-1").unwrap().name(), "iso-8859-1");
+        assert_eq!(super::encoding_from_label("Latin1").unwrap().name(), "windows-1252");
+        assert!(super::encoding_from_label("plop").is_none());
+    }
+    #[test]
+    fn requested_encoding() {
+        let dir = TempDir::new();
+        fs::write(dir.file(".editorconfig"), "root = true\n[*]\ncharset = latin1\n").unwrap();
+        let path = dir.file("file.txt");
+        fs::write(&path, "é").unwrap();
+        let buf = Buffer::from_file(&path).unwrap();
+        assert_eq!(buf.get_encoding().name(), "iso-8859-1");
+        let utf8 = Some(encoding::all::UTF_8 as encoding::EncodingRef);
+        let buf = Buffer::from_file_with_encoding(&path, utf8).unwrap();
+        assert_eq!(buf.get_encoding().name(), "utf-8");
+        assert_eq!(buf.to_string(), "é");
+    }
+    #[test]
+    fn find_unencodable_char() {
+        let mut buf = Buffer::from_str("Noel\nNoël €");
+        buf.set_encoding(encoding::all::ISO_8859_1);
+        assert_eq!(buf.find_unencodable_char(), Some((1, 5, '€')));
+    }
+    #[test]
+    fn bom() {
+        let dir = TempDir::new();
+        let path = dir.file("bom.txt");
+        fs::write(&path, b"\xEF\xBB\xBFhello").unwrap();
+        let mut buf = Buffer::from_file(&path).unwrap();
+        assert_eq!(buf.to_string(), "hello");
+        assert!(buf.has_bom());
+        assert_eq!(buf.get_encoding().name(), "utf-8");
+        buf.save().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"\xEF\xBB\xBFhello");
+        buf.set_bom(false).unwrap();
+        buf.save().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"hello");
+    }
+    #[test]
+    fn write_atomic() {
+        let dir = TempDir::new();
+        let path = dir.file("atomic.txt");
+        let mut bak = path.clone().into_os_string();
+        bak.push(".bak");
+        fs::write(&path, b"old").unwrap();
+        super::write_atomic(&path, b"new", true).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::read(&bak).unwrap(), b"old");
+    }
+    #[test]
+    fn save() {
+        let dir = TempDir::new();
+        let path = dir.file("new.txt");
+        let mut buf = Buffer::new_file(&path);
+        assert!(!buf.is_dirty());
+        assert!(!path.exists());
+        buf.insert(0, "éa  \nb");
+        buf.trim_trailing_whitespace = true;
+        buf.set_encoding(encoding::all::ASCII);
+        // the whitespaces are trimmed on a copy, kept only once written
+        assert!(buf.save().is_err());
+        assert_eq!(buf.to_string(), "éa  \nb");
+        assert!(!path.exists());
+        buf.remove(0..1);
+        buf.save().unwrap();
+        assert_eq!(buf.to_string(), "a\nb");
+        assert!(!buf.is_dirty());
+        assert_eq!(fs::read(&path).unwrap(), b"a\nb");
+        assert!(!Buffer::from_file(&path).unwrap().is_read_only());
+
+        // a read-only buffer is left untouched
+        buf.insert(1, "  ");
+        buf.set_read_only(true);
+        assert!(buf.save().is_err());
+        assert_eq!(buf.to_string(), "a  \nb");
+        assert_eq!(fs::read(&path).unwrap(), b"a\nb");
+        let copy = dir.file("copy.txt");
+        buf.save_as(&copy).unwrap();
+        assert!(!buf.is_read_only());
+        assert_eq!(fs::read(&copy).unwrap(), b"a\nb");
+    }
+    #[test]
+    fn is_dirty() {
+        let mut buf = Buffer::from_str("hello");
+        assert!(!buf.is_dirty());
+        let saved = buf.clone();
+        buf.insert(0, "oh ");
+        assert!(buf.is_dirty());
+        buf.restore(saved);
+        assert!(!buf.is_dirty());
+    }
+    #[test]
+    fn check_disk() {
+        let dir = TempDir::new();
+        let path = dir.file("check.txt");
+        fs::write(&path, b"hello").unwrap();
+        let mut buf = Buffer::from_file(&path).unwrap();
+        assert_eq!(buf.check_disk(), DiskState::Unchanged);
+
+        // undoing after a save keeps the file saved as the known one
+        let previous = buf.clone();
+        buf.insert(5, " world");
+        buf.save().unwrap();
+        buf.restore(previous);
+        assert_eq!(buf.check_disk(), DiskState::Unchanged);
+        buf.save().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"hello");
+
+        fs::write(&path, b"hello world").unwrap();
+        assert_eq!(buf.check_disk(), DiskState::Modified);
+        assert!(buf.save().is_err());
+        buf.reload().unwrap();
+        assert_eq!(buf.to_string(), "hello world");
+        assert_eq!(buf.check_disk(), DiskState::Unchanged);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(buf.check_disk(), DiskState::Deleted);
+    }
+
+    #[test]
+    fn load_large_file() {
+        let dir = TempDir::new();
+        let path = dir.file("large.txt");
+        // multi-byte chars across the chunk boundaries
+        let text = "é\tline\n".repeat(CHUNK_SIZE / 4);
+        fs::write(&path, &text).unwrap();
+        let (mut buf, events) = Buffer::load_large_file(&path, None);
+        assert!(buf.is_loading());
+        assert!(buf.save().is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), text);
+        // chosen while loading
+        buf.set_read_only(true);
+        let file = events
+            .iter()
+            .filter_map(|e| match e {
+                LoadEvent::Done(file) => Some(file.unwrap()),
+                LoadEvent::Progress(..) => None,
+            })
+            .next()
+            .unwrap();
+        buf.finish_loading(file);
+        assert_eq!(buf.to_string(), text);
+        assert!(!buf.is_loading());
+        assert!(buf.is_read_only());
+        assert_eq!(buf.get_encoding().name(), "utf-8");
+        assert_eq!(buf.check_disk(), DiskState::Unchanged);
+    }
 }
@@ -0,0 +1,180 @@
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::buffer::encoding_from_label;
+
+pub const USAGE: &str = "Usage: nonedit [OPTIONS] [[+LINE] FILE[:LINE[:COL]]]...
+
+Open the given files, after restoring the views of the previous session
+unless --wait is given.
+
+Options:
+  -                      read the text to edit from the standard input
+  +LINE[:COL]            put the cursor on LINE of the next file
+  -e, --encoding LABEL   decode the files with the given encoding
+  -r, --readonly         open the files in read-only mode
+  -n, --new-window       open a new window even if one is already running
+  -w, --wait             wait for the files to be closed before returning
+  -h, --help             print this help
+  -V, --version          print the version
+";
+
+/// A file given on the command line, with the position to put the cursor at
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileArg {
+    pub path: PathBuf,
+    /// 1-based line and column
+    pub line: Option<usize>,
+    pub col: Option<usize>,
+}
+
+/// The options given on the command line
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Options {
+    pub files: Vec<FileArg>,
+    pub stdin: bool,
+    pub encoding: Option<String>,
+    pub readonly: bool,
+    pub new_window: bool,
+    pub wait: bool,
+}
+
+/// What the command line asks to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Run(Options),
+    Help,
+    Version,
+}
+
+/// parse the command line arguments, without the program name
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut options = Options::default();
+    let mut position: Option<(usize, Option<usize>)> = None;
+    let mut only_files = false;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if only_files {
+            options.files.push(file_arg(&arg, position.take()));
+            continue;
+        }
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "-r" | "--readonly" => options.readonly = true,
+            "-n" | "--new-window" => options.new_window = true,
+            "-w" | "--wait" => options.wait = true,
+            "-e" | "--encoding" => {
+                let label = args.next().ok_or_else(|| format!("{} needs an encoding", arg))?;
+                options.encoding = Some(check_encoding(&label)?);
+            }
+            "-" => options.stdin = true,
+            "--" => only_files = true,
+            _ if arg.starts_with("--encoding=") => {
+                options.encoding = Some(check_encoding(&arg["--encoding=".len()..])?);
+            }
+            _ if arg.starts_with('+') => {
+                position = Some(parse_position(&arg[1..]).ok_or_else(|| format!("Invalid position: {}", arg))?);
+            }
+            _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            _ => options.files.push(file_arg(&arg, position.take())),
+        }
+    }
+    if position.is_some() {
+        return Err("A +LINE position must be followed by a file".to_owned());
+    }
+    Ok(Command::Run(options))
+}
+
+fn check_encoding(label: &str) -> Result<String, String> {
+    match encoding_from_label(label) {
+        Some(_) => Ok(label.to_owned()),
+        None => Err(format!("Unknown encoding: {}", label)),
+    }
+}
+
+/// parse `LINE` or `LINE:COL`
+fn parse_position(s: &str) -> Option<(usize, Option<usize>)> {
+    let mut parts = s.splitn(2, ':');
+    let line = parts.next()?.parse().ok()?;
+    let col = match parts.next() {
+        Some(col) => Some(col.parse().ok()?),
+        None => None,
+    };
+    Some((line, col))
+}
+
+/// split a trailing `:number` from the argument
+fn split_number(s: &str) -> Option<(&str, usize)> {
+    let i = s.rfind(':')?;
+    if i == 0 {
+        return None;
+    }
+    let n = s[i + 1..].parse().ok()?;
+    Some((&s[..i], n))
+}
+
+/// build the file argument, reading the `file:line:col` syntax unless the whole argument is an existing file
+fn file_arg(arg: &str, position: Option<(usize, Option<usize>)>) -> FileArg {
+    let (path, line, col) = match position {
+        Some((line, col)) => (arg, Some(line), col),
+        None if Path::new(arg).exists() => (arg, None, None),
+        None => match split_number(arg) {
+            Some((rest, n)) if Path::new(rest).exists() => (rest, Some(n), None),
+            Some((rest, n)) => match split_number(rest) {
+                Some((path, line)) => (path, Some(line), Some(n)),
+                None => (rest, Some(n), None),
+            },
+            None => (arg, None, None),
+        },
+    };
+    FileArg {
+        path: PathBuf::from(path),
+        line,
+        col,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(args: &[&str]) -> Result<Command, String> {
+        parse(args.iter().map(|a| a.to_string()))
+    }
+
+    fn file(path: &str, line: Option<usize>, col: Option<usize>) -> FileArg {
+        FileArg {
+            path: PathBuf::from(path),
+            line,
+            col,
+        }
+    }
+
+    #[test]
+    fn parse_args() {
+        let options = match run(&["-r", "a.rs:12:5", "+3", "b.rs", "c.rs:7", "--encoding", "latin1", "-"]) {
+            Ok(Command::Run(options)) => options,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(
+            options.files,
+            vec![
+                file("a.rs", Some(12), Some(5)),
+                file("b.rs", Some(3), None),
+                file("c.rs", Some(7), None),
+            ]
+        );
+        assert!(options.readonly && options.stdin && !options.wait);
+        assert_eq!(options.encoding, Some("latin1".to_owned()));
+
+        assert_eq!(run(&["--", "-w"]).unwrap(), Command::Run(Options {
+            files: vec![file("-w", None, None)],
+            ..Default::default()
+        }));
+        assert_eq!(run(&["a", "--help"]), Ok(Command::Help));
+        assert!(run(&["--encoding", "nope"]).is_err());
+        assert!(run(&["--unknown"]).is_err());
+        assert!(run(&["a", "+2"]).is_err());
+    }
+}
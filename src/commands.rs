@@ -2,8 +2,9 @@ use std::sync::Mutex;
 use lazy_static::lazy_static;
 use clipboard2::*;
 use crate::keybinding::KeyBinding;
+use crate::styling::{syntax_names, theme_names, STYLE};
 use crate::view::{Direction, View, ViewCmd};
-use crate::window::EditorWindow;
+use crate::window::{EditorWindow, AUTO_SYNTAX};
 use crate::window::WindowCmd;
 
 
@@ -113,8 +114,15 @@ lazy_static! {
 
 pub mod view {
     use crate::commands::*;
+    use crate::buffer::{Indentation, LineFeed};
     use crate::view::ViewCmd;
-    use crate::SETTINGS;
+
+    fn set_indent_width(v: &mut View<'_>, n: u32) {
+        match v.get_indentation() {
+            Indentation::Space(_) => v.set_indentation(Indentation::Space(n)),
+            Indentation::Tab(_) => v.set_tab_size(n as usize),
+        }
+    }
 
     pub fn get_all() -> Vec<Box<dyn ViewCmd>> {
         let mut v = Vec::<Box<dyn ViewCmd>>::new();
@@ -148,6 +156,16 @@ pub mod view {
                 v.insert(&s);
             },
         ));
+        v.push(GenericViewCommand::new_box(
+            "ToggleReadOnly",
+            "Forbid or allow the modifications of the file",
+            &["Alt-Shift-R"],
+            |v| {
+                let read_only = !v.is_read_only();
+                v.set_read_only(read_only);
+                v.set_message(if read_only { "Read-only" } else { "Editable" });
+            },
+        ));
         v.push(GenericViewCommand::new_box(
             "End",
             "Go to the end of the line",
@@ -190,19 +208,125 @@ pub mod view {
             &["NumpadEnter", "Return"],
             |v| v.insert_linefeed(),
         ));
-        v.push(GenericViewCommand::new_box("Tab", "Add a tabulation", &["Tab"], |v| {
-            if SETTINGS.read().unwrap().get("indentWithSpace").unwrap() {
-                let n = SETTINGS.read().unwrap().get::<usize>("tabSize").unwrap();
-                let p = v.col_idx();
-                let cible = ((p + n) / n) * n;
-
-                for _ in 0..cible - p {
-                    v.insert_char(' ');
+        v.push(GenericViewCommand::new_box(
+            "Tab",
+            "Add a tabulation or indent the selected lines",
+            &["Tab"],
+            |v| v.indent(),
+        ));
+        v.push(GenericViewCommand::new_box(
+            "Outdent",
+            "Remove one level of indentation from the selected lines",
+            &["Shift-Tab"],
+            |v| v.outdent(),
+        ));
+        v.push(GenericViewCommand::new_box(
+            "ConvertIndentationToSpaces",
+            "Convert the indentation of the whole file to spaces",
+            &["Ctrl-Alt-Shift-S"],
+            |v| {
+                let n = v.get_indentation().width() as u32;
+                v.convert_indentation_to_spaces(n);
+            },
+        ));
+        v.push(GenericViewCommand::new_box(
+            "ConvertIndentationToTabs",
+            "Convert the indentation of the whole file to tabulations",
+            &["Ctrl-Alt-Shift-T"],
+            |v| {
+                let n = v.get_tab_size() as u32;
+                v.convert_indentation_to_tabs(n);
+            },
+        ));
+        v.push(GenericViewCommand::new_box(
+            "Reindent",
+            "Normalize the indentation of the whole file",
+            &["Ctrl-Alt-Shift-I"],
+            |v| {
+                let indentation = v.get_indentation();
+                v.reindent(indentation, indentation);
+            },
+        ));
+        v.push(GenericViewCommand::new_box(
+            "IndentUsingSpaces",
+            "Use spaces for the indentation of this file",
+            &["Alt-Shift-S"],
+            |v| {
+                let n = v.get_indentation().width() as u32;
+                v.set_indentation(Indentation::Space(n));
+            },
+        ));
+        v.push(GenericViewCommand::new_box(
+            "IndentUsingTabs",
+            "Use tabulations for the indentation of this file",
+            &["Alt-Shift-T"],
+            |v| {
+                let n = v.get_tab_size() as u32;
+                v.set_indentation(Indentation::Tab(n));
+            },
+        ));
+        v.push(GenericViewCommand::new_box(
+            "IndentWidth2",
+            "Set the indentation width of this file to 2",
+            &["Alt-Shift-Key2"],
+            |v| set_indent_width(v, 2),
+        ));
+        v.push(GenericViewCommand::new_box(
+            "IndentWidth4",
+            "Set the indentation width of this file to 4",
+            &["Alt-Shift-Key4"],
+            |v| set_indent_width(v, 4),
+        ));
+        v.push(GenericViewCommand::new_box(
+            "IndentWidth8",
+            "Set the indentation width of this file to 8",
+            &["Alt-Shift-Key8"],
+            |v| set_indent_width(v, 8),
+        ));
+        v.push(GenericViewCommand::new_box(
+            "DetectIndentation",
+            "Detect the indentation from the content of this file",
+            &["Alt-Shift-D"],
+            |v| v.detect_indentation(),
+        ));
+        v.push(GenericViewCommand::new_box(
+            "LineEndingLF",
+            "Convert the line endings of this file to LF",
+            &["Alt-Shift-L"],
+            |v| v.convert_linefeed(LineFeed::LF),
+        ));
+        v.push(GenericViewCommand::new_box(
+            "LineEndingCRLF",
+            "Convert the line endings of this file to CRLF",
+            &["Alt-Shift-W"],
+            |v| v.convert_linefeed(LineFeed::CRLF),
+        ));
+        v.push(GenericViewCommand::new_box(
+            "LineEndingCR",
+            "Convert the line endings of this file to CR",
+            &["Alt-Shift-M"],
+            |v| v.convert_linefeed(LineFeed::CR),
+        ));
+        v.push(GenericViewCommand::new_box(
+            "AddBom",
+            "Write a byte order mark at the begining of the file",
+            &["Alt-Shift-B"],
+            |v| {
+                if let Err(e) = v.set_bom(true) {
+                    v.set_message(e.to_string());
                 }
-            } else {
-                v.insert_char('\t');
-            }
-        }));
+            },
+        ));
+        v.push(GenericViewCommand::new_box(
+            "RemoveBom",
+            "Remove the byte order mark at the begining of the file",
+            &["Ctrl-Alt-Shift-B"],
+            |v| {
+                if let Err(e) = v.set_bom(false) {
+                    v.set_message(e.to_string());
+                }
+            },
+        ));
         v.push(GenericViewCommand::new_box(
             "Backspace",
             "delete the char at left  or the selection",
@@ -291,13 +415,16 @@ pub mod view {
             |v| v.move_page(Direction::Down, true),
         ));
         v.push(GenericViewCommand::new_box("Save", "Save file", &["Ctrl-S"], |v| {
-            v.save();
+            if let Err(e) = v.save() {
+                v.set_message(e.to_string());
+            }
         }));
         v
     }
 }
 
 pub mod window {
+    use crate::buffer::encoding_from_label;
     use crate::commands::*;
     use nfd;
     use crate::window::WindowCmd;
@@ -310,10 +437,124 @@ pub mod window {
             &["Ctrl-O"],
             |w| {
                 if let Ok(nfd::Response::Okay(file)) = nfd::open_file_dialog(None, None) {
-                    w.add_new_view(Some(file))
+                    w.add_new_view(Some(file));
                 }
             },
         ));
+        v.push(GenericWindowCommand::new_box(
+            "NextView",
+            "Show the next opened file",
+            &["Ctrl-Tab"],
+            |w| w.next_view(),
+        ));
+        v.push(GenericWindowCommand::new_box(
+            "PreviousView",
+            "Show the previous opened file",
+            &["Ctrl-Shift-Tab"],
+            |w| w.previous_view(),
+        ));
+        v.push(GenericWindowCommand::new_box(
+            "CloseView",
+            "Close the current file, asking to save it if modified",
+            &["Ctrl-W"],
+            |w| w.request_close_view(),
+        ));
+        v.push(GenericWindowCommand::new_box(
+            "FilterThroughCommand",
+            "Replace the selection, or the whole file, by its output through a shell command",
+            &["Ctrl-Alt-F"],
+            |w| {
+                w.prompt("Filter through command:", "", |w, command| {
+                    if !command.trim().is_empty() {
+                        w.get_current_view_mut().filter(command);
+                    }
+                })
+            },
+        ));
+        v.push(GenericWindowCommand::new_box(
+            "Quit",
+            "Close the window, asking to save the modified files",
+            &["Ctrl-Q"],
+            |w| w.request_close(),
+        ));
+        v.push(GenericWindowCommand::new_box(
+            "ReopenWithEncoding",
+            "Reload the file with the given encoding",
+            &["Ctrl-Alt-R"],
+            |w| {
+                let current = w.get_current_view().get_encoding().to_owned();
+                w.prompt("Reopen with encoding:", &current, |w, label| {
+                    let v = w.get_current_view_mut();
+                    match encoding_from_label(label) {
+                        Some(encoding) => {
+                            if let Err(e) = v.reopen_with_encoding(encoding) {
+                                v.set_message(e.to_string());
+                            }
+                        }
+                        None => v.set_message(format!("Unknown encoding: {}", label)),
+                    }
+                });
+            },
+        ));
+        v.push(GenericWindowCommand::new_box(
+            "SaveWithEncoding",
+            "Save the file with the given encoding",
+            &["Ctrl-Alt-S"],
+            |w| {
+                let current = w.get_current_view().get_encoding().to_owned();
+                w.prompt("Save with encoding:", &current, |w, label| {
+                    let v = w.get_current_view_mut();
+                    match encoding_from_label(label) {
+                        Some(encoding) => {
+                            if let Err(e) = v.save_with_encoding(encoding) {
+                                v.set_message(e.to_string());
+                            }
+                        }
+                        None => v.set_message(format!("Unknown encoding: {}", label)),
+                    }
+                });
+            },
+        ));
+        v.push(GenericWindowCommand::new_box(
+            "ZoomIn",
+            "Make the font bigger",
+            &["Ctrl-Add", "Ctrl-Equals", "Ctrl-Shift-Equals"],
+            |w| w.zoom(1.0),
+        ));
+        v.push(GenericWindowCommand::new_box(
+            "ZoomOut",
+            "Make the font smaller",
+            &["Ctrl-Subtract", "Ctrl-Minus"],
+            |w| w.zoom(-1.0),
+        ));
+        v.push(GenericWindowCommand::new_box(
+            "ResetZoom",
+            "Go back to the font size of the settings",
+            &["Ctrl-Key0", "Ctrl-Numpad0"],
+            |w| w.reset_zoom(),
+        ));
+        v.push(GenericWindowCommand::new_box(
+            "SetSyntax",
+            "Choose the syntax of the current file, overriding the detected one",
+            &["Ctrl-Shift-L"],
+            |w| {
+                let current = w.get_current_view().get_syntax().to_owned();
+                let mut names = vec![AUTO_SYNTAX.to_owned()];
+                names.extend(syntax_names());
+                w.pick("Syntax:", names, &current, |_, _| (), |w, name| w.set_syntax(name));
+            },
+        ));
+        v.push(GenericWindowCommand::new_box(
+            "SelectTheme",
+            "Pick the color theme, previewing each one",
+            &["Ctrl-K"],
+            |w| {
+                let current = STYLE.read().unwrap().name.clone();
+                w.pick("Theme:", theme_names(), &current, |w, name| w.set_theme(name), |w, name| {
+                    w.set_theme(name)
+                });
+            },
+        ));
         v
     }
 }
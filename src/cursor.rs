@@ -1,7 +1,24 @@
 use crate::buffer::Buffer;
-use crate::SETTINGS;
 use std::cell::RefCell;
 use std::rc::Rc;
+use unicode_width::UnicodeWidthStr;
+
+/// return the number of cells taken by a grapheme cluster on screen, tabs excepted
+/// East Asian wide chars and emoji sequences take two cells, line endings none.
+pub fn grapheme_width(g: &str) -> usize {
+    match g.chars().next() {
+        None | Some('\r') | Some('\n') | Some('\0') => 0,
+        _ => g.width(),
+    }
+}
+
+/// return the column after a grapheme cluster drawn at the given column
+pub fn next_col(col: usize, g: &str, tabsize: usize) -> usize {
+    match g {
+        "\t" => ((col + tabsize) / tabsize) * tabsize,
+        _ => col + grapheme_width(g),
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Point {
@@ -17,27 +34,22 @@ pub struct Index {
 }
 
 impl Into<Index> for Point {
+    /// A column in the middle of a tab or of a wide char gives the index of this char.
     fn into(self) -> Index {
-        let tabsize: u32 = SETTINGS.read().unwrap().get("tabSize").unwrap();
+        let tabsize = self.buffer.borrow().get_tab_size();
         let index = self.buffer.borrow().line_to_char(self.line);
         let mut col_idx = 0;
-        let mut col: u32 = 0;
-        for c in self.buffer.borrow().chars_on_line(self.line).take(self.col) {
-            match c {
-                '\t' => {
-                    col = ((col + tabsize) / tabsize) * tabsize;
-                }
-                '\r' | '\n' | '\0' => (),
-                // Bom hiding. TODO: rework
-                '\u{feff}' | '\u{fffe}' => (),
-                _ => {
-                    col += 1;
-                }
+        let mut col = 0;
+        for g in self.buffer.borrow().graphemes_on_line(self.line) {
+            if col >= self.col || g.starts_with('\r') || g.starts_with('\n') {
+                break;
             }
-            col_idx += 1;
-            if col as usize == self.col {
+            let next = next_col(col, &g, tabsize);
+            if next > self.col {
                 break;
             }
+            col = next;
+            col_idx += g.chars().count();
         }
         Index {
             index: index + col_idx,
@@ -48,26 +60,21 @@ impl Into<Index> for Point {
 
 impl Into<Point> for Index {
     fn into(self) -> Point {
-        let tabsize: u32 = SETTINGS.read().unwrap().get("tabSize").unwrap();
+        let tabsize = self.buffer.borrow().get_tab_size();
         let mut col = 0;
         let line = self.buffer.borrow().char_to_line(self.index);
         let maxc = self.index - self.buffer.borrow().line_to_char(line);
-        for c in self.buffer.borrow().chars_on_line(line).take(maxc) {
-            match c {
-                '\t' => {
-                    col = ((col + tabsize) / tabsize) * tabsize;
-                }
-                '\r' | '\n' | '\0' => (),
-                // Bom hiding. TODO: rework
-                '\u{feff}' | '\u{fffe}' => (),
-                _ => {
-                    col += 1;
-                }
+        let mut chars = 0;
+        for g in self.buffer.borrow().graphemes_on_line(line) {
+            if chars >= maxc {
+                break;
             }
+            col = next_col(col, &g, tabsize);
+            chars += g.chars().count();
         }
         Point {
             line,
-            col: col as usize,
+            col,
             buffer: self.buffer,
         }
     }
@@ -96,21 +103,10 @@ impl Cursor {
     }
 
     fn line_last_col(&self, line: usize) -> usize {
-        let tabsize: usize = SETTINGS.read().unwrap().get("tabSize").unwrap();
+        let tabsize = self.buffer.borrow().get_tab_size();
         let mut col: usize = 0;
-        //let line = self.buffer.borrow().char_to_line(line);
-        for c in self.buffer.borrow().chars_on_line(line) {
-            match c {
-                '\t' => {
-                    col = ((col + tabsize) / tabsize) * tabsize;
-                }
-                '\r' | '\n' | '\0' => (),
-                // Bom hiding. TODO: rework
-                '\u{feff}' | '\u{fffe}' => (),
-                _ => {
-                    col += 1;
-                }
-            }
+        for g in self.buffer.borrow().graphemes_on_line(line) {
+            col = next_col(col, &g, tabsize);
         }
         col
     }
@@ -182,35 +178,23 @@ impl Cursor {
         self.set_line(self.line.saturating_add(amount));
     }
 
-    /// Move the cursor left
+    /// Move the cursor left, over a whole grapheme cluster
     /// Do nothing if already on the first char of the buffer
-    /// move line up if on the first char of the current line
+    /// move line up if on the first char of the current line, a crlf being a single cluster
     pub fn left(&mut self) {
         if self.index > 0 {
-            let line_idx = self.buffer.borrow().char_to_line(self.index);
-            let line_idx_char = self.buffer.borrow().line_to_char(line_idx);
-            // handle crlf and lf
-            if self.index == line_idx_char {
-                let idx = self.buffer.borrow().line_to_last_char(line_idx - 1);
-                self.set_index(idx);
-            } else {
-                self.set_index(self.index - 1);
-            }
+            let idx = self.buffer.borrow().prev_grapheme_boundary(self.index);
+            self.set_index(idx);
         }
     }
 
-    /// Move the cursor right
+    /// Move the cursor right, over a whole grapheme cluster
     /// Do nothing if already on the last char of the buffer
     /// move line down if on the last char of the current line
     pub fn right(&mut self) {
-        let line_idx = self.buffer.borrow().char_to_line(self.index);
-        let line_idx_char = self.buffer.borrow().line_to_last_char(line_idx);
-        // handle crlf and lf
-        if self.index == line_idx_char {
-            let idx = self.buffer.borrow().line_to_char(line_idx + 1);
+        if self.index < self.buffer.borrow().len_chars() {
+            let idx = self.buffer.borrow().next_grapheme_boundary(self.index);
             self.set_index(idx);
-        } else {
-            self.set_index(self.index + 1);
         }
     }
 
@@ -0,0 +1,163 @@
+/// A line of a diff between two texts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffLine<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Above this number of compared lines, changed blocks are not diffed line by line
+const MAX_LCS_SIZE: usize = 4_000_000;
+
+/// compute the line diff turning `old` into `new`
+pub fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // skip the common prefix and suffix
+    let prefix = old.iter().zip(new.iter()).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let mut result: Vec<DiffLine<'a>> = old[..prefix].iter().map(|l| DiffLine::Same(l)).collect();
+    if old_mid.len() * new_mid.len() > MAX_LCS_SIZE {
+        result.extend(old_mid.iter().map(|l| DiffLine::Removed(l)));
+        result.extend(new_mid.iter().map(|l| DiffLine::Added(l)));
+    } else {
+        result.extend(lcs_diff(old_mid, new_mid));
+    }
+    result.extend(old[old.len() - suffix..].iter().map(|l| DiffLine::Same(l)));
+    result
+}
+
+fn lcs_diff<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<DiffLine<'a>> {
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let width = new.len() + 1;
+    let mut lcs = vec![0usize; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i * width + j] = if old[i] == new[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                std::cmp::max(lcs[(i + 1) * width + j], lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut result = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            result.push(DiffLine::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[(i + 1) * width + j] >= lcs[i * width + j + 1] {
+            result.push(DiffLine::Removed(old[i]));
+            i += 1;
+        } else {
+            result.push(DiffLine::Added(new[j]));
+            j += 1;
+        }
+    }
+    result.extend(old[i..].iter().map(|l| DiffLine::Removed(l)));
+    result.extend(new[j..].iter().map(|l| DiffLine::Added(l)));
+    result
+}
+
+/// return the number of (added, removed) lines of a diff
+pub fn count_changes(diff: &[DiffLine<'_>]) -> (usize, usize) {
+    diff.iter().fold((0, 0), |(added, removed), l| match l {
+        DiffLine::Added(_) => (added + 1, removed),
+        DiffLine::Removed(_) => (added, removed + 1),
+        DiffLine::Same(_) => (added, removed),
+    })
+}
+
+/// format the diff in the unified format, with `context` lines around each change
+pub fn unified(diff: &[DiffLine<'_>], context: usize) -> String {
+    let mut out = String::new();
+    let changed: Vec<usize> = diff
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| match l {
+            DiffLine::Same(_) => false,
+            _ => true,
+        })
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut i = 0;
+    while i < changed.len() {
+        // group the changes closer than two contexts into one hunk
+        let start = changed[i].saturating_sub(context);
+        let mut last = changed[i];
+        while i + 1 < changed.len() && changed[i + 1] <= last + 2 * context + 1 {
+            i += 1;
+            last = changed[i];
+        }
+        let end = std::cmp::min(last + context + 1, diff.len());
+
+        let old_start = diff[..start].iter().filter(|l| !is_added(l)).count() + 1;
+        let new_start = diff[..start].iter().filter(|l| !is_removed(l)).count() + 1;
+        let old_len = diff[start..end].iter().filter(|l| !is_added(l)).count();
+        let new_len = diff[start..end].iter().filter(|l| !is_removed(l)).count();
+        out.push_str(&format!("@@ -{},{} +{},{} @@\n", old_start, old_len, new_start, new_len));
+        for l in &diff[start..end] {
+            match l {
+                DiffLine::Same(l) => out.push_str(&format!(" {}\n", l)),
+                DiffLine::Removed(l) => out.push_str(&format!("-{}\n", l)),
+                DiffLine::Added(l) => out.push_str(&format!("+{}\n", l)),
+            }
+        }
+        i += 1;
+    }
+    out
+}
+
+fn is_added(l: &DiffLine<'_>) -> bool {
+    match l {
+        DiffLine::Added(_) => true,
+        _ => false,
+    }
+}
+
+fn is_removed(l: &DiffLine<'_>) -> bool {
+    match l {
+        DiffLine::Removed(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff() {
+        let d = diff_lines("a\nb\nc\nd", "a\nc\nx\nd");
+        assert_eq!(
+            d,
+            vec![
+                DiffLine::Same("a"),
+                DiffLine::Removed("b"),
+                DiffLine::Same("c"),
+                DiffLine::Added("x"),
+                DiffLine::Same("d"),
+            ]
+        );
+        assert_eq!(count_changes(&d), (1, 1));
+    }
+
+    #[test]
+    fn unified_format() {
+        let d = diff_lines("a\nb\nc\nd\ne\nf\ng\nh", "a\nb\nc\nd\ne\nf\ng\nH");
+        assert_eq!(unified(&d, 1), "@@ -7,2 +7,2 @@\n g\n-h\n+H\n");
+        assert_eq!(unified(&diff_lines("a", "a"), 3), "");
+    }
+}
@@ -0,0 +1,372 @@
+use encoding;
+use encoding::EncodingRef;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::buffer::LineFeed;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndentStyle {
+    Tab,
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndentSize {
+    Tab,
+    Columns(usize),
+}
+
+/// The properties of the `.editorconfig` files that apply to a file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EditorConfig {
+    pub indent_style: Option<IndentStyle>,
+    pub indent_size: Option<IndentSize>,
+    pub tab_width: Option<usize>,
+    pub end_of_line: Option<LineFeed>,
+    pub charset: Option<String>,
+    pub trim_trailing_whitespace: Option<bool>,
+    pub insert_final_newline: Option<bool>,
+}
+
+impl EditorConfig {
+    /// Locate and parse the `.editorconfig` files applying to the given file,
+    /// from its directory up to the first file declaring `root = true`
+    pub fn from_path(filename: &Path) -> Self {
+        let filename = fs::canonicalize(filename).unwrap_or_else(|_| filename.to_owned());
+        let mut files = Vec::new();
+        for dir in filename.ancestors().skip(1) {
+            let config_file = dir.join(".editorconfig");
+            if let Ok(content) = fs::read_to_string(&config_file) {
+                let file = ConfigFile::parse(&content);
+                let root = file.root;
+                files.push((dir.to_owned(), file));
+                if root {
+                    break;
+                }
+            }
+        }
+
+        // the nearest file has the precedence, so apply it last
+        let mut properties = HashMap::new();
+        for (dir, file) in files.iter().rev() {
+            file.collect_properties(dir, &filename, &mut properties);
+        }
+        EditorConfig::from_properties(&properties)
+    }
+
+    fn from_properties(properties: &HashMap<String, String>) -> Self {
+        let get = |key: &str| properties.get(key).map(|v| v.as_str());
+        EditorConfig {
+            indent_style: match get("indent_style") {
+                Some("tab") => Some(IndentStyle::Tab),
+                Some("space") => Some(IndentStyle::Space),
+                _ => None,
+            },
+            indent_size: match get("indent_size") {
+                Some("tab") => Some(IndentSize::Tab),
+                Some(n) => n.parse().ok().filter(|n| *n > 0).map(IndentSize::Columns),
+                None => None,
+            },
+            tab_width: get("tab_width").and_then(|n| n.parse().ok()).filter(|n| *n > 0),
+            end_of_line: match get("end_of_line") {
+                Some("lf") => Some(LineFeed::LF),
+                Some("crlf") => Some(LineFeed::CRLF),
+                Some("cr") => Some(LineFeed::CR),
+                _ => None,
+            },
+            charset: get("charset")
+                .filter(|c| ["latin1", "utf-8", "utf-8-bom", "utf-16be", "utf-16le"].contains(c))
+                .map(|c| c.to_owned()),
+            trim_trailing_whitespace: get("trim_trailing_whitespace").and_then(parse_bool),
+            insert_final_newline: get("insert_final_newline").and_then(parse_bool),
+        }
+    }
+
+    /// return the encoding matching the `charset` property
+    pub fn encoding(&self) -> Option<EncodingRef> {
+        match self.charset.as_ref().map(|c| c.as_str()) {
+            Some("latin1") => Some(encoding::all::ISO_8859_1),
+            Some("utf-8") | Some("utf-8-bom") => Some(encoding::all::UTF_8),
+            Some("utf-16be") => Some(encoding::all::UTF_16BE),
+            Some("utf-16le") => Some(encoding::all::UTF_16LE),
+            _ => None,
+        }
+    }
+
+    /// return the width of one indentation level, if specified
+    pub fn indent_width(&self) -> Option<usize> {
+        match self.indent_size {
+            Some(IndentSize::Columns(n)) => Some(n),
+            Some(IndentSize::Tab) => self.tab_width,
+            None => None,
+        }
+    }
+
+    /// return the width of a tabulation, if specified
+    /// Default to `indent_size` as the specification requires
+    pub fn tab_width(&self) -> Option<usize> {
+        match (self.tab_width, self.indent_size) {
+            (Some(n), _) => Some(n),
+            (None, Some(IndentSize::Columns(n))) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Default)]
+struct ConfigFile {
+    root: bool,
+    sections: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl ConfigFile {
+    fn parse(content: &str) -> Self {
+        let mut file = ConfigFile::default();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                file.sections.push((line[1..line.len() - 1].to_owned(), Vec::new()));
+            } else if let Some(eq) = line.find('=') {
+                let key = line[..eq].trim().to_lowercase();
+                let value = line[eq + 1..].trim().to_lowercase();
+                match file.sections.last_mut() {
+                    Some(section) => section.1.push((key, value)),
+                    None => {
+                        if key == "root" {
+                            file.root = value == "true";
+                        }
+                    }
+                }
+            }
+        }
+        file
+    }
+
+    /// add the properties of the sections matching the file to `properties`
+    fn collect_properties(&self, dir: &Path, filename: &Path, properties: &mut HashMap<String, String>) {
+        let relative: PathBuf = match filename.strip_prefix(dir) {
+            Ok(p) => p.to_owned(),
+            Err(_) => return,
+        };
+        let relative = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect::<Vec<String>>()
+            .join("/");
+
+        for (glob, props) in &self.sections {
+            if glob_match(glob, &relative) {
+                for (key, value) in props {
+                    properties.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Match a section name against a path relative to the `.editorconfig` directory
+/// A glob without `/` matches the file name in any sub-directory
+pub fn glob_match(glob: &str, path: &str) -> bool {
+    let glob = if glob.contains('/') {
+        glob.trim_start_matches('/').to_owned()
+    } else {
+        format!("**/{}", glob)
+    };
+    let path: Vec<char> = path.chars().collect();
+    expand_braces(&glob)
+        .iter()
+        .any(|g| match_chars(&g.chars().collect::<Vec<char>>(), &path))
+}
+
+/// expand `{a,b}` and `{1..3}` alternatives into simple globs
+fn expand_braces(glob: &str) -> Vec<String> {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut depth = 0;
+    let mut start = None;
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '{' => {
+                if depth == 0 {
+                    start = Some(i);
+                }
+                depth += 1;
+            }
+            '}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    let open = start.unwrap();
+                    let prefix: String = chars[..open].iter().collect();
+                    let inner: String = chars[open + 1..i].iter().collect();
+                    let suffix: String = chars[i + 1..].iter().collect();
+
+                    let alternatives = match numeric_range(&inner) {
+                        Some(range) => range,
+                        None => split_alternatives(&inner),
+                    };
+                    if alternatives.len() < 2 && numeric_range(&inner).is_none() {
+                        // `{single}` is matched literally
+                        let literal = format!("{}\\{{{}\\}}", prefix, inner);
+                        return expand_braces(&suffix)
+                            .into_iter()
+                            .map(|s| format!("{}{}", literal, s))
+                            .collect();
+                    }
+                    let mut result = Vec::new();
+                    for alt in alternatives {
+                        result.extend(expand_braces(&format!("{}{}{}", prefix, alt, suffix)));
+                    }
+                    return result;
+                }
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    vec![glob.to_owned()]
+}
+
+fn numeric_range(inner: &str) -> Option<Vec<String>> {
+    let mut parts = inner.splitn(2, "..");
+    let start: i64 = parts.next()?.parse().ok()?;
+    let end: i64 = parts.next()?.parse().ok()?;
+    let (start, end) = if start <= end { (start, end) } else { (end, start) };
+    if end - start > 10_000 {
+        return None;
+    }
+    Some((start..=end).map(|n| n.to_string()).collect())
+}
+
+fn split_alternatives(inner: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                current.push(c);
+                if let Some(c) = chars.next() {
+                    current.push(c);
+                }
+            }
+            '{' => {
+                depth += 1;
+                current.push(c);
+            }
+            '}' => {
+                depth -= 1;
+                current.push(c);
+            }
+            ',' if depth == 0 => result.push(std::mem::replace(&mut current, String::new())),
+            _ => current.push(c),
+        }
+    }
+    result.push(current);
+    result
+}
+
+fn match_chars(glob: &[char], path: &[char]) -> bool {
+    match glob.first() {
+        None => path.is_empty(),
+        Some('\\') if glob.len() > 1 => path.first() == Some(&glob[1]) && match_chars(&glob[2..], &path[1..]),
+        Some('*') if glob.get(1) == Some(&'*') => {
+            let rest = &glob[2..];
+            // `**/` also matches no directory at all
+            if rest.first() == Some(&'/') && match_chars(&rest[1..], path) {
+                return true;
+            }
+            (0..=path.len()).any(|i| match_chars(rest, &path[i..]))
+        }
+        Some('*') => {
+            let rest = &glob[1..];
+            for i in 0..=path.len() {
+                if match_chars(rest, &path[i..]) {
+                    return true;
+                }
+                if i < path.len() && path[i] == '/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => !path.is_empty() && path[0] != '/' && match_chars(&glob[1..], &path[1..]),
+        Some('[') => match glob.iter().skip(1).position(|c| *c == ']') {
+            Some(end) if !path.is_empty() => {
+                let set = &glob[1..end + 1];
+                let (negate, set) = match set.first() {
+                    Some('!') | Some('^') => (true, &set[1..]),
+                    _ => (false, set),
+                };
+                let c = path[0];
+                let mut found = false;
+                let mut i = 0;
+                while i < set.len() {
+                    if i + 2 < set.len() && set[i + 1] == '-' {
+                        found |= set[i] <= c && c <= set[i + 2];
+                        i += 3;
+                    } else {
+                        found |= set[i] == c;
+                        i += 1;
+                    }
+                }
+                found != negate && c != '/' && match_chars(&glob[end + 2..], &path[1..])
+            }
+            Some(_) => false,
+            None => path.first() == Some(&'[') && match_chars(&glob[1..], &path[1..]),
+        },
+        Some(c) => path.first() == Some(c) && match_chars(&glob[1..], &path[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("*", "main.rs"));
+        assert!(glob_match("*", "src/main.rs"));
+        assert!(glob_match("*.rs", "src/main.rs"));
+        assert!(!glob_match("*.rs", "src/main.c"));
+        assert!(glob_match("*.{rs,toml}", "Cargo.toml"));
+        assert!(glob_match("src/*.rs", "src/main.rs"));
+        assert!(!glob_match("src/*.rs", "src/a/main.rs"));
+        assert!(glob_match("src/**.rs", "src/a/main.rs"));
+        assert!(glob_match("/src/**/*.rs", "src/main.rs"));
+        assert!(glob_match("[Mm]akefile", "Makefile"));
+        assert!(!glob_match("[!M]akefile", "Makefile"));
+        assert!(glob_match("file?.txt", "file1.txt"));
+        assert!(glob_match("file{1..3}.txt", "file2.txt"));
+        assert!(!glob_match("file{1..3}.txt", "file4.txt"));
+        assert!(glob_match("{single}.txt", "{single}.txt"));
+    }
+
+    #[test]
+    fn parse() {
+        let file = ConfigFile::parse(
+            "root = true\n\n# comment\n[*]\nindent_style = space\nindent_size = 4\n\n[Makefile]\nindent_style = Tab\n",
+        );
+        assert!(file.root);
+        let mut properties = HashMap::new();
+        file.collect_properties(Path::new("/project"), Path::new("/project/src/Makefile"), &mut properties);
+        let config = EditorConfig::from_properties(&properties);
+        assert_eq!(config.indent_style, Some(IndentStyle::Tab));
+        assert_eq!(config.indent_size, Some(IndentSize::Columns(4)));
+        assert_eq!(config.tab_width(), Some(4));
+    }
+}
@@ -0,0 +1,200 @@
+// Single-instance mode: the first process listens on a Unix domain socket
+// and the next ones forward their command line to it.
+
+#[cfg(unix)]
+pub use self::unix::{forward, Client, Server};
+
+#[cfg(not(unix))]
+pub use self::other::{forward, Client, Server};
+
+#[cfg(unix)]
+mod unix {
+    use std::fs;
+    use std::io;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use directories::ProjectDirs;
+
+    use crate::cli::Options;
+
+    /// path of the socket, in the runtime directory of the user
+    /// Without one, a private directory is created in the temporary directory.
+    fn socket_path() -> io::Result<PathBuf> {
+        let dirs = ProjectDirs::from("com", "pepone42", "nonedit");
+        let dir = match dirs.as_ref().and_then(|d| d.runtime_dir()) {
+            Some(dir) => dir.to_owned(),
+            None => {
+                let uid = unsafe { libc::getuid() };
+                let dir = std::env::temp_dir().join(format!("nonedit-{}", uid));
+                match fs::DirBuilder::new().mode(0o700).create(&dir) {
+                    Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+                    result => result?,
+                }
+                dir
+            }
+        };
+        check_private(&dir)?;
+        Ok(dir.join("nonedit.sock"))
+    }
+
+    /// fail unless the directory belongs to the user and only the user can access it
+    fn check_private(dir: &Path) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(dir)?;
+        if !metadata.is_dir() || metadata.uid() != unsafe { libc::getuid() } || metadata.mode() & 0o077 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is not a private directory of the user", dir.display()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// fail if the socket exists and belongs to another user
+    fn check_owner(path: &Path) -> io::Result<()> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.uid() != unsafe { libc::getuid() } => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} belongs to another user", path.display()),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// make the paths absolute, as the running instance has another working directory
+    fn absolute(options: &Options) -> io::Result<Options> {
+        let cwd = std::env::current_dir()?;
+        let mut options = options.clone();
+        for file in &mut options.files {
+            file.path = cwd.join(&file.path);
+        }
+        Ok(options)
+    }
+
+    /// send the command line to the running instance
+    /// Return false if there is none. With `--wait`, block until the files are closed.
+    pub fn forward(options: &Options) -> io::Result<bool> {
+        let path = socket_path()?;
+        check_owner(&path)?;
+        let mut stream = match UnixStream::connect(&path) {
+            Ok(stream) => stream,
+            Err(_) => return Ok(false),
+        };
+        let request = serde_json::to_string(&absolute(options)?).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(b"\n")?;
+        // the running instance closes the connection once the files are opened, or closed with --wait
+        let mut answer = Vec::new();
+        stream.read_to_end(&mut answer)?;
+        Ok(true)
+    }
+
+    /// Another process waiting for the files it asked to open
+    /// It is notified when this is dropped.
+    #[derive(Debug)]
+    pub struct Client {
+        _stream: UnixStream,
+    }
+
+    /// The socket receiving the command lines of the other processes
+    #[derive(Debug)]
+    pub struct Server {
+        listener: UnixListener,
+        path: PathBuf,
+    }
+
+    impl Server {
+        /// listen for the other processes
+        /// A socket left by a crashed instance is replaced.
+        pub fn bind() -> io::Result<Server> {
+            let path = socket_path()?;
+            check_owner(&path)?;
+            if path.exists() && UnixStream::connect(&path).is_err() {
+                fs::remove_file(&path)?;
+            }
+            let listener = UnixListener::bind(&path)?;
+            listener.set_nonblocking(true)?;
+            Ok(Server { listener, path })
+        }
+
+        /// return the next command line sent by another process, without blocking
+        /// The client is returned when it waits for its files to be closed.
+        pub fn accept(&self) -> Option<(Options, Option<Client>)> {
+            let (stream, _) = self.listener.accept().ok()?;
+            match read_request(&stream) {
+                Ok(options) => {
+                    let client = if options.wait { Some(Client { _stream: stream }) } else { None };
+                    Some((options, client))
+                }
+                Err(e) => {
+                    eprintln!("Invalid request from another instance: {}", e);
+                    None
+                }
+            }
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    fn read_request(stream: &UnixStream) -> io::Result<Options> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{check_owner, check_private};
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        #[test]
+        fn private_dir() {
+            let dir = std::env::temp_dir().join(format!("nonedit-test-instance-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+            assert!(check_private(&dir).is_err());
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+            assert!(check_private(&dir).is_ok());
+            assert!(check_owner(&dir.join("nonedit.sock")).is_ok());
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod other {
+    use std::io;
+
+    use crate::cli::Options;
+
+    /// there is no single-instance mode on this platform
+    pub fn forward(_: &Options) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    #[derive(Debug)]
+    pub struct Client;
+
+    #[derive(Debug)]
+    pub struct Server;
+
+    impl Server {
+        pub fn bind() -> io::Result<Server> {
+            Err(io::Error::new(io::ErrorKind::Other, "Single instance mode is not supported"))
+        }
+
+        pub fn accept(&self) -> Option<(Options, Option<Client>)> {
+            None
+        }
+    }
+}
@@ -1,10 +1,17 @@
 mod buffer;
+mod cli;
 mod commands;
+mod diff;
+mod editorconfig;
+mod instance;
 mod keybinding;
 mod styling;
 mod view;
 mod window;
 mod nanovg;
+mod recovery;
+mod session;
+mod shell;
 mod cursor;
 
 use lazy_static::lazy_static;
@@ -14,6 +21,7 @@ use std::env;
 use std::fs;
 use std::io::Read;
 use std::path::PathBuf;
+use std::process;
 use std::sync::RwLock;
 
 use directories::ProjectDirs;
@@ -41,5 +49,20 @@ lazy_static! {
 }
 
 fn main() {
-    window::start(env::args().nth(1));
+    let options = match cli::parse(env::args().skip(1)) {
+        Ok(cli::Command::Run(options)) => options,
+        Ok(cli::Command::Help) => {
+            print!("{}", cli::USAGE);
+            return;
+        }
+        Ok(cli::Command::Version) => {
+            println!("nonedit {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Err(e) => {
+            eprintln!("nonedit: {}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        }
+    };
+    window::start(options);
 }
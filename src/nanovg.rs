@@ -2,6 +2,7 @@ use gl;
 use glutin;
 use glutin::GlContext;
 use nanovg;
+use std::path::PathBuf;
 
 pub struct MonoFontMetrics {
     pub advance: f32,
@@ -10,11 +11,138 @@ pub struct MonoFontMetrics {
     pub line_height: f32,
 }
 
+/// The faces of the monospace font
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontFace {
+    Regular,
+    Bold,
+    Italic,
+    BoldItalic,
+}
+
+impl FontFace {
+    pub fn new(bold: bool, italic: bool) -> Self {
+        match (bold, italic) {
+            (false, false) => FontFace::Regular,
+            (true, false) => FontFace::Bold,
+            (false, true) => FontFace::Italic,
+            (true, true) => FontFace::BoldItalic,
+        }
+    }
+
+    /// name of the font loaded for this face
+    fn font_name(self) -> &'static str {
+        match self {
+            FontFace::Regular => "Mono",
+            FontFace::Bold => "MonoBold",
+            FontFace::Italic => "MonoItalic",
+            FontFace::BoldItalic => "MonoBoldItalic",
+        }
+    }
+}
+
+const FACES: [FontFace; 4] = [FontFace::Regular, FontFace::Bold, FontFace::Italic, FontFace::BoldItalic];
+
+const DEFAULT_FAMILY: &str = "Ubuntu Mono";
+
+/// return the regular, bold, italic and bold italic faces of a font shipped with the editor
+fn bundled_faces(family: &str) -> Option<[&'static [u8]; 4]> {
+    match family.to_lowercase().replace(' ', "").as_str() {
+        "ubuntumono" => Some([
+            include_bytes!("monofont/UbuntuMono-Regular.ttf"),
+            include_bytes!("monofont/UbuntuMono-Bold.ttf"),
+            include_bytes!("monofont/UbuntuMono-Italic.ttf"),
+            include_bytes!("monofont/UbuntuMono-BoldItalic.ttf"),
+        ]),
+        // there is no italic face
+        "inconsolata" => Some([
+            include_bytes!("monofont/Inconsolata-Regular.ttf"),
+            include_bytes!("monofont/Inconsolata-Bold.ttf"),
+            include_bytes!("monofont/Inconsolata-Regular.ttf"),
+            include_bytes!("monofont/Inconsolata-Bold.ttf"),
+        ]),
+        _ => None,
+    }
+}
+
+/// Where the monospace font comes from
+#[derive(Debug, Clone)]
+pub enum FontSource {
+    /// a family shipped with the editor
+    Bundled(String),
+    /// a font file, used for every face
+    File(PathBuf),
+}
+
+/// load the faces of the font, each one falling back on the given font files for the missing glyphs
+fn load_fonts(context: &nanovg::Context, source: &FontSource, fallbacks: &[PathBuf]) {
+    let family = match source {
+        FontSource::File(path) => {
+            if FACES.iter().all(|face| nanovg::Font::from_file(context, face.font_name(), path).is_ok()) {
+                None
+            } else {
+                eprintln!("Can't load the font {}", path.display());
+                Some(DEFAULT_FAMILY)
+            }
+        }
+        FontSource::Bundled(family) => Some(family.as_str()),
+    };
+    if let Some(family) = family {
+        let faces = bundled_faces(family).unwrap_or_else(|| {
+            eprintln!("Unknown font family: {}", family);
+            bundled_faces(DEFAULT_FAMILY).unwrap()
+        });
+        for (face, data) in FACES.iter().zip(faces.iter()) {
+            nanovg::Font::from_memory(context, face.font_name(), data).expect("Failed to load font");
+        }
+    }
+
+    // the fallbacks are searched in order
+    let fallbacks: Vec<nanovg::Font<'_>> = fallbacks
+        .iter()
+        .filter(|path| path.exists())
+        .enumerate()
+        .filter_map(|(i, path)| match nanovg::Font::from_file(context, &format!("Fallback{}", i), path) {
+            Ok(font) => Some(font),
+            Err(_) => {
+                eprintln!("Can't load the fallback font {}", path.display());
+                None
+            }
+        })
+        .collect();
+    for face in FACES.iter() {
+        let font = nanovg::Font::find(context, face.font_name()).unwrap();
+        for fallback in &fallbacks {
+            font.add_fallback(*fallback);
+        }
+    }
+}
+
+/// How a char is drawn, besides its color
+#[derive(Debug, Clone, Copy)]
+pub struct TextStyle {
+    pub face: FontFace,
+    pub underline: bool,
+    /// color of the cell behind the char, None to keep what is already drawn
+    pub background: Option<nanovg::Color>,
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        TextStyle {
+            face: FontFace::Regular,
+            underline: false,
+            background: None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum DisplayList {
     Move(f32, f32),
     Color(nanovg::Color),
-    Char(char),
+    /// a grapheme cluster and the number of cells it takes
+    Grapheme(String, TextStyle, usize),
     Rect(f32, f32),
     Clear,
 }
@@ -49,7 +177,19 @@ impl Canvas {
 
     /// Draw a char
     pub fn draw_char(&mut self, c: char) {
-        self.cmdlist.push(DisplayList::Char(c));
+        self.cmdlist.push(DisplayList::Grapheme(c.to_string(), TextStyle::default(), 1));
+    }
+
+    /// Draw a grapheme cluster over the given number of cells, with the given face, underline and background
+    pub fn draw_grapheme(&mut self, g: &str, style: TextStyle, cells: usize) {
+        self.cmdlist.push(DisplayList::Grapheme(g.to_owned(), style, cells));
+    }
+
+    /// Draw a string, one char after the other
+    pub fn draw_str(&mut self, s: &str) {
+        for c in s.chars() {
+            self.draw_char(c);
+        }
     }
 
     /// move the pointer to x,y
@@ -67,7 +207,7 @@ pub struct System {
 }
 
 impl System {
-    pub fn new(title: &str, width: f32, height: f32, font_size: f32) -> Self {
+    pub fn new(title: &str, width: f32, height: f32, font: &FontSource, font_size: f32, fallbacks: &[PathBuf]) -> Self {
         let mut events_loop = glutin::EventsLoop::new();
         let window = glutin::WindowBuilder::new()
             .with_title(title)
@@ -85,8 +225,7 @@ impl System {
             .build()
             .expect("Initialization of NanoVG failed!");
 
-        let mono_font = nanovg::Font::from_memory(&nvgcontext, "Mono", include_bytes!("monofont/UbuntuMono-B.ttf"))
-            .expect("Failed to load font");
+        load_fonts(&nvgcontext, font, fallbacks);
 
         let text_option = nanovg::TextOptions {
             color: nanovg::Color::new(1.0, 1.0, 1.0, 1.0),
@@ -103,30 +242,53 @@ impl System {
             window.get_inner_size().unwrap().to_physical(hidpi_factor)
         );
 
+        let font_metrics = MonoFontMetrics {
+            advance: 0.0,
+            ascender: 0.0,
+            descender: 0.0,
+            line_height: 0.0,
+        };
+        let mut system = System {
+            events_loop,
+            window,
+            nvgcontext,
+            text_option,
+            canvas: Canvas::new(font_metrics),
+        };
+        system.canvas.font_metrics = system.measure_font();
+        system
+    }
+
+    fn measure_font(&self) -> MonoFontMetrics {
+        let mono_font = nanovg::Font::find(&self.nvgcontext, FontFace::Regular.font_name()).unwrap();
+        let text_option = self.text_option;
         let mut advance: f32 = 0.0;
         let mut text_metrics: nanovg::TextMetrics = nanovg::TextMetrics {ascender: 0.0,descender: 0.0,line_height: 0.0};
 
-        nvgcontext.frame((window.get_inner_size().unwrap().width as _, window.get_inner_size().unwrap().height as _), hidpi_factor as _, |frame| {
+        self.nvgcontext.frame((self.log_width() as _, self.log_height() as _), self.hidpi_factor() as _, |frame| {
             advance = frame.text_bounds(mono_font, (0.0, 0.0), "_", text_option).0;
             text_metrics = frame.text_metrics(mono_font, text_option);
         });
 
-        let font_metrics = MonoFontMetrics {
+        MonoFontMetrics {
             advance,
             ascender: text_metrics.ascender,
             descender: text_metrics.descender,
             line_height: text_metrics.line_height,
-        };
-
-        System {
-            events_loop,
-            window,
-            nvgcontext,
-            text_option,
-            canvas: Canvas::new(font_metrics),
         }
     }
 
+    /// return the size of the font
+    pub fn font_size(&self) -> f32 {
+        self.text_option.size
+    }
+
+    /// change the size of the font and measure it again
+    pub fn set_font_size(&mut self, size: f32) {
+        self.text_option.size = size;
+        self.canvas.font_metrics = self.measure_font();
+    }
+
     pub fn log_width(&self) -> f64 {
         self.window.get_inner_size().unwrap().width
     }
@@ -152,8 +314,9 @@ impl System {
         let mut y: f32 = 0.0;
         let mut color = nanovg::Color::from_rgb(0, 0, 0);
 
-        let font = nanovg::Font::find(&self.nvgcontext, "Mono").unwrap();
+        let font = |face: FontFace| nanovg::Font::find(&self.nvgcontext, face.font_name()).unwrap();
         let mut text_option = self.text_option;
+        let metrics = &self.canvas.font_metrics;
 
         let phy_width = self.phy_width();
         let phy_height = self.phy_height();
@@ -175,10 +338,31 @@ impl System {
                             Default::default(),
                         );
                     }
-                    DisplayList::Char(c) => {
+                    DisplayList::Grapheme(ref g, style, cells) => {
+                        let width = metrics.advance * cells as f32;
+                        // y is the baseline
+                        if let Some(background) = style.background {
+                            let top = y - metrics.descender - metrics.line_height;
+                            frame.path(
+                                |p| {
+                                    p.rect((x, top), (width, metrics.line_height));
+                                    p.fill(background, Default::default());
+                                },
+                                Default::default(),
+                            );
+                        }
                         text_option.color = color;
-                        frame.text(font, (x, y), c.to_string(), text_option);
-                        x += self.canvas.font_metrics.advance;
+                        frame.text(font(style.face), (x, y), g, text_option);
+                        if style.underline {
+                            frame.path(
+                                |p| {
+                                    p.rect((x, y + 1.0), (width, 1.0));
+                                    p.fill(color, Default::default());
+                                },
+                                Default::default(),
+                            );
+                        }
+                        x += width;
                     }
                     DisplayList::Clear => unsafe {
                         gl::ClearColor(color.red(), color.green(), color.blue(), color.alpha());
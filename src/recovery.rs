@@ -0,0 +1,353 @@
+use serde_derive::{Deserialize, Serialize};
+use serde_json;
+use std::cell::RefCell;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use directories::ProjectDirs;
+use fs2::FileExt;
+
+use crate::buffer::{hash_bytes, write_atomic, Buffer};
+
+/// A copy of the content of a modified buffer, written so that it survives a crash
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecoveryFile {
+    /// file of the buffer, None for buffers without file
+    pub filename: Option<PathBuf>,
+    pub content: String,
+    /// id of the editor instance which wrote it, its files are left alone while it holds its lock
+    pub instance: String,
+}
+
+impl RecoveryFile {
+    /// read a recovery file
+    pub fn load(path: &Path) -> io::Result<RecoveryFile> {
+        let content = fs::read_to_string(path)?;
+        serde_json::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// return true if the recovery file was written after the last modification of the original file
+    fn is_newer_than_original(&self, path: &Path) -> bool {
+        let original = match self.filename {
+            Some(ref filename) => filename,
+            None => return true,
+        };
+        let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+        match (modified(path), modified(original)) {
+            (Some(recovery), Some(original)) => recovery > original,
+            // the original file doesn't exist anymore
+            (Some(_), None) => true,
+            _ => false,
+        }
+    }
+}
+
+struct Entry {
+    buffer: Rc<RefCell<Buffer>>,
+    path: PathBuf,
+    revision: usize,
+}
+
+/// Keep a recovery file for each modified buffer
+/// Recovery files are removed once the buffer is saved or the editor exits normally.
+pub struct Journal {
+    dir: Option<PathBuf>,
+    // the process id alone could be reused after a crash
+    instance: String,
+    // locked while the editor runs, released by the system if it crashes
+    lock: Option<File>,
+    entries: Vec<Entry>,
+    untitled: usize,
+    // files of the last autosave, written in the background
+    writing: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl Journal {
+    /// create a journal writing in the data directory of the editor
+    pub fn new() -> Self {
+        let dir = ProjectDirs::from("com", "pepone42", "nonedit").map(|d| d.data_dir().join("recovery"));
+        Journal::with_dir(dir)
+    }
+
+    fn with_dir(dir: Option<PathBuf>) -> Self {
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+        Journal {
+            dir,
+            instance: format!("{}-{}", process::id(), start),
+            lock: None,
+            entries: Vec::new(),
+            untitled: 0,
+            writing: None,
+        }
+    }
+
+    /// return the recovery files left by instances which are not running anymore,
+    /// and which are newer than their original
+    pub fn pending(&self) -> Vec<(PathBuf, RecoveryFile)> {
+        let dir = match self.dir {
+            Some(ref dir) => dir,
+            None => return Vec::new(),
+        };
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut pending: Vec<(PathBuf, RecoveryFile)> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().map_or(false, |e| e == "json"))
+            .filter_map(|p| match RecoveryFile::load(&p) {
+                Ok(file) => Some((p, file)),
+                Err(e) => {
+                    eprintln!("Invalid recovery file {}: {}", p.display(), e);
+                    None
+                }
+            })
+            .filter(|(_, file)| !is_running(dir, &file.instance))
+            .collect();
+        // outdated copies are useless
+        for (path, file) in &pending {
+            if !file.is_newer_than_original(path) {
+                let _ = fs::remove_file(path);
+            }
+        }
+        pending.retain(|(path, file)| path.exists() && file.is_newer_than_original(path));
+        pending.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // the lock files of the instances which left nothing to recover
+        if let Ok(entries) = fs::read_dir(dir) {
+            for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                let instance = match path.file_stem().and_then(|s| s.to_str()) {
+                    Some(instance) if path.extension().map_or(false, |e| e == "lock") => instance.to_owned(),
+                    _ => continue,
+                };
+                if !pending.iter().any(|(_, f)| f.instance == instance) && !is_running(dir, &instance) {
+                    let _ = fs::remove_file(path);
+                }
+            }
+        }
+        pending
+    }
+
+    /// write the buffers modified since the last autosave and remove the recovery files
+    /// of the buffers which are saved or closed
+    /// The contents are copied right away and written in a background thread.
+    /// Return the error of the previous autosave, whose files are written again,
+    /// or the error of the lock file.
+    pub fn autosave(&mut self, buffers: &[Rc<RefCell<Buffer>>]) -> io::Result<()> {
+        let dir = match self.dir {
+            Some(ref dir) => dir.clone(),
+            None => return Ok(()),
+        };
+        let result = self.wait();
+        if result.is_err() {
+            for entry in &mut self.entries {
+                entry.revision = 0;
+            }
+        }
+        // copying a large file every few seconds would be too slow
+        let copied = |b: &Rc<RefCell<Buffer>>| b.borrow().is_dirty() && !b.borrow().is_large();
+        // the lock tells the other instances that the files are not left by a crash
+        if self.lock.is_none() && buffers.iter().any(copied) {
+            fs::create_dir_all(&dir)?;
+            self.lock = Some(lock_instance(&dir, &self.instance)?);
+        }
+
+        let mut removed = Vec::new();
+        let mut kept = Vec::new();
+        for entry in self.entries.drain(..) {
+            let keep = {
+                let b = entry.buffer.borrow();
+                let open = buffers.iter().any(|o| Rc::ptr_eq(o, &entry.buffer));
+                // the buffer could have been saved under another name
+                let same_file = match b.get_filename() {
+                    Some(filename) => entry.path == recovery_path(&dir, &self.instance, Some(filename), 0),
+                    None => true,
+                };
+                open && b.is_dirty() && same_file
+            };
+            if keep {
+                kept.push(entry);
+            } else {
+                removed.push(entry.path);
+            }
+        }
+        self.entries = kept;
+
+        let mut written = Vec::new();
+        for buffer in buffers.iter().filter(|b| copied(b)) {
+            let revision = buffer.borrow().get_revision();
+            let index = match self.entries.iter().position(|e| Rc::ptr_eq(&e.buffer, buffer)) {
+                Some(i) if self.entries[i].revision == revision => continue,
+                Some(i) => i,
+                None => {
+                    let path = {
+                        let b = buffer.borrow();
+                        if b.get_filename().is_none() {
+                            self.untitled += 1;
+                        }
+                        recovery_path(&dir, &self.instance, b.get_filename(), self.untitled)
+                    };
+                    self.entries.push(Entry {
+                        buffer: buffer.clone(),
+                        path,
+                        revision: 0,
+                    });
+                    self.entries.len() - 1
+                }
+            };
+            let file = {
+                let b = buffer.borrow();
+                RecoveryFile {
+                    filename: b.get_filename().map(|f| f.to_owned()),
+                    content: b.to_string(),
+                    instance: self.instance.clone(),
+                }
+            };
+            written.push((self.entries[index].path.clone(), file));
+            self.entries[index].revision = revision;
+        }
+
+        self.writing = Some(thread::spawn(move || {
+            for path in removed {
+                let _ = fs::remove_file(path);
+            }
+            for (path, file) in written {
+                let data = serde_json::to_vec(&file).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                write_atomic(&path, &data, false)?;
+            }
+            Ok(())
+        }));
+        result
+    }
+
+    /// wait for the files of the last autosave to be written
+    pub fn wait(&mut self) -> io::Result<()> {
+        match self.writing.take() {
+            Some(writing) => writing
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "Autosave thread panicked"))),
+            None => Ok(()),
+        }
+    }
+
+    /// remove every recovery file written by this journal, and its lock file
+    pub fn clear(&mut self) {
+        let _ = self.wait();
+        for entry in self.entries.drain(..) {
+            let _ = fs::remove_file(&entry.path);
+        }
+        if let (Some(dir), Some(_)) = (&self.dir, self.lock.take()) {
+            let _ = fs::remove_file(lock_path(dir, &self.instance));
+        }
+    }
+}
+
+/// return the path of the recovery file of a buffer
+/// Buffers without file are numbered by `untitled`.
+/// The instance id is part of it so that two instances don't clash.
+fn recovery_path(dir: &Path, instance: &str, filename: Option<&Path>, untitled: usize) -> PathBuf {
+    match filename {
+        Some(filename) => {
+            let hash = hash_bytes(filename.to_string_lossy().as_bytes());
+            dir.join(format!("{:016x}-{}.json", hash, instance))
+        }
+        None => dir.join(format!("untitled-{}-{}.json", instance, untitled)),
+    }
+}
+
+fn lock_path(dir: &Path, instance: &str) -> PathBuf {
+    dir.join(format!("{}.lock", instance))
+}
+
+/// create and lock the lock file of an instance
+/// It is locked under another name first, so that it is never seen unlocked.
+fn lock_instance(dir: &Path, instance: &str) -> io::Result<File> {
+    let tmp = dir.join(format!("{}.lock.tmp", instance));
+    let file = File::create(&tmp)?;
+    file.try_lock_exclusive()?;
+    fs::rename(&tmp, lock_path(dir, instance))?;
+    Ok(file)
+}
+
+/// return true if the instance still holds its lock file
+fn is_running(dir: &Path, instance: &str) -> bool {
+    match File::open(lock_path(dir, instance)) {
+        Ok(file) => file.try_lock_exclusive().is_err(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn autosave() {
+        let dir = std::env::temp_dir().join(format!("nonedit-recovery-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut journal = Journal::with_dir(Some(dir.clone()));
+
+        let clean = Rc::new(RefCell::new(Buffer::new()));
+        let dirty = Rc::new(RefCell::new(Buffer::new()));
+        dirty.borrow_mut().insert(0, "unsaved");
+        journal.autosave(&[clean.clone(), dirty.clone()]).unwrap();
+        journal.wait().unwrap();
+        // the recovery file and the lock file
+        assert_eq!(files(&dir), 2);
+
+        // the file is removed once the buffer is closed
+        journal.autosave(&[clean.clone()]).unwrap();
+        journal.wait().unwrap();
+        assert_eq!(files(&dir), 1);
+
+        journal.autosave(&[dirty.clone()]).unwrap();
+        journal.clear();
+        assert_eq!(files(&dir), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lock() {
+        let dir = std::env::temp_dir().join(format!("nonedit-recovery-lock-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let lock = lock_instance(&dir, "1-1").unwrap();
+        assert!(is_running(&dir, "1-1"));
+        assert!(!is_running(&dir, "1-2"));
+        let file = RecoveryFile {
+            filename: None,
+            content: "unsaved".to_owned(),
+            instance: "1-1".to_owned(),
+        };
+        fs::write(dir.join("untitled-1-1-1.json"), serde_json::to_vec(&file).unwrap()).unwrap();
+
+        // the files of a running instance are left alone
+        assert!(Journal::with_dir(Some(dir.clone())).pending().is_empty());
+
+        // the lock is released when the instance crashes, its lock file is left behind
+        drop(lock);
+        assert!(!is_running(&dir, "1-1"));
+        let pending = Journal::with_dir(Some(dir.clone())).pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].1, file);
+        assert!(lock_path(&dir, "1-1").exists());
+
+        // it is removed once there is nothing left to recover
+        fs::remove_file(&pending[0].0).unwrap();
+        assert!(Journal::with_dir(Some(dir.clone())).pending().is_empty());
+        assert_eq!(files(&dir), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+use serde_derive::{Deserialize, Serialize};
+use serde_json;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use directories::ProjectDirs;
+
+/// The state of a view saved between two launches
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ViewSession {
+    pub filename: Option<PathBuf>,
+    /// content of the buffers without file, so that they are not lost on exit
+    pub content: Option<String>,
+    pub cursor: usize,
+    pub selection: Option<(usize, usize)>,
+    pub line_start: usize,
+    pub col_start: usize,
+}
+
+/// The state of the editor saved between two launches
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub width: f32,
+    pub height: f32,
+    pub current_view: usize,
+    pub views: Vec<ViewSession>,
+}
+
+impl Session {
+    fn path() -> Option<PathBuf> {
+        let dirs = ProjectDirs::from("com", "pepone42", "nonedit")?;
+        Some(dirs.data_dir().join("session.json"))
+    }
+
+    /// load the session saved by the previous launch
+    pub fn load() -> Option<Session> {
+        let content = fs::read_to_string(Session::path()?).ok()?;
+        match serde_json::from_str(&content) {
+            Ok(session) => Some(session),
+            Err(e) => {
+                eprintln!("Invalid session file: {}", e);
+                None
+            }
+        }
+    }
+
+    /// save the session for the next launch
+    pub fn save(&self) -> io::Result<()> {
+        let path = Session::path().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No data directory"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let content = serde_json::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        fs::write(path, content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let session = Session {
+            width: 800.0,
+            height: 600.0,
+            current_view: 1,
+            views: vec![
+                ViewSession {
+                    filename: Some(PathBuf::from("/tmp/file.rs")),
+                    cursor: 12,
+                    selection: Some((3, 12)),
+                    line_start: 2,
+                    ..Default::default()
+                },
+                ViewSession {
+                    content: Some("scratch".to_owned()),
+                    ..Default::default()
+                },
+            ],
+        };
+        let json = serde_json::to_string(&session).unwrap();
+        assert_eq!(serde_json::from_str::<Session>(&json).unwrap(), session);
+    }
+}
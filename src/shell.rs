@@ -0,0 +1,72 @@
+use std::io;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// The result of a shell command run as a filter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// exit code, None if the command was killed by a signal
+    pub code: Option<i32>,
+}
+
+impl FilterOutput {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.arg("/C").arg(command);
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.arg("-c").arg(command);
+        c
+    }
+}
+
+/// run a shell command with `input` as standard input and collect its output
+pub fn filter(command: &str, input: &str) -> io::Result<FilterOutput> {
+    let mut child = shell(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // write from another thread, the command could fill its output pipe before reading all its input
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_owned();
+    let writer = thread::spawn(move || {
+        // the command may exit without reading its input
+        let _ = stdin.write_all(input.as_bytes());
+    });
+    let output = child.wait_with_output()?;
+    let _ = writer.join();
+
+    Ok(FilterOutput {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        code: output.status.code(),
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_command() {
+        let output = filter("sort", "b\nc\na\n").unwrap();
+        assert_eq!(output.stdout, "a\nb\nc\n");
+        assert!(output.success());
+
+        let output = filter("echo oops >&2; exit 3", "").unwrap();
+        assert_eq!(output.stderr, "oops\n");
+        assert_eq!(output.code, Some(3));
+    }
+}
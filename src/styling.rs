@@ -1,27 +1,278 @@
 use lazy_static::lazy_static;
-use crate::buffer::Buffer;
-use std::iter::FromIterator;
+use crate::buffer::{hash_bytes, Buffer};
+use crate::SETTINGS;
+use directories::ProjectDirs;
+use ropey::Rope;
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::fs;
+use std::iter::{repeat, FromIterator};
 use std::ops::Deref;
 use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::slice;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::time::UNIX_EPOCH;
+use syntect::dumps::{dump_to_file, from_dump_file};
 use syntect::highlighting::{HighlightIterator, HighlightState, Highlighter, Style, Theme, ThemeSet};
 use syntect::parsing::{ParseState, ScopeStack, SyntaxReference, SyntaxSet};
 
 lazy_static! {
-    pub static ref THEMESET: ThemeSet = ThemeSet::load_defaults();
-    pub static ref SYNTAXSET: SyntaxSet = SyntaxSet::load_defaults_newlines();
-    pub static ref STYLE: Styling<'static> = Styling::new();
+    pub static ref THEMESET: ThemeSet = load_themes();
+    pub static ref SYNTAXSET: SyntaxSet = load_syntaxes();
+    pub static ref STYLE: RwLock<Styling<'static>> = RwLock::new(Styling::new());
+}
+
+const DEFAULT_THEME: &str = "Solarized (dark)";
+
+/// load the bundled themes and the .tmTheme files of the `themes` folder of the user config directory
+fn load_themes() -> ThemeSet {
+    let mut themes = ThemeSet::load_defaults();
+    if let Some(dirs) = ProjectDirs::from("com", "pepone42", "nonedit") {
+        let dir = dirs.config_dir().join("themes");
+        if dir.is_dir() {
+            if let Err(e) = themes.add_from_folder(&dir) {
+                eprintln!("Can't load the themes of {}: {}", dir.display(), e);
+            }
+        }
+    }
+    themes
+}
+
+/// load the bundled syntaxes and the .sublime-syntax files of the `syntaxes` folder of the user config directory
+/// The compiled set is cached, it is compiled again when the files change.
+fn load_syntaxes() -> SyntaxSet {
+    let dirs = match ProjectDirs::from("com", "pepone42", "nonedit") {
+        Some(dirs) => dirs,
+        None => return SyntaxSet::load_defaults_newlines(),
+    };
+    let dir = dirs.config_dir().join("syntaxes");
+    let mut files = Vec::new();
+    syntax_files(&dir, &mut files);
+    if files.is_empty() {
+        return SyntaxSet::load_defaults_newlines();
+    }
+    let cache = dirs.cache_dir().join(format!("syntaxes-{:016x}.packdump", syntaxes_key(&mut files)));
+    if let Ok(syntaxes) = from_dump_file(&cache) {
+        return syntaxes;
+    }
+    build_syntaxes(&dir, &cache)
+}
+
+/// compile the bundled syntaxes with the ones of `dir`, and dump the result to `cache`
+fn build_syntaxes(dir: &Path, cache: &Path) -> SyntaxSet {
+    let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+    if let Err(e) = builder.add_from_folder(dir, true) {
+        eprintln!("Can't load the syntaxes of {}: {}", dir.display(), e);
+        return SyntaxSet::load_defaults_newlines();
+    }
+    let syntaxes = builder.build();
+
+    if let Some(cache_dir) = cache.parent() {
+        // the previous dumps are outdated
+        if let Ok(entries) = fs::read_dir(cache_dir) {
+            for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                if path.extension().map_or(false, |e| e == "packdump") {
+                    let _ = fs::remove_file(path);
+                }
+            }
+        }
+        let _ = fs::create_dir_all(cache_dir);
+    }
+    if let Err(e) = dump_to_file(&syntaxes, cache) {
+        eprintln!("Can't cache the syntaxes in {}: {}", cache.display(), e);
+    }
+    syntaxes
+}
+
+/// collect the .sublime-syntax files of a folder and its subfolders
+fn syntax_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if path.is_dir() {
+            syntax_files(&path, files);
+        } else if path.extension().map_or(false, |e| e == "sublime-syntax") {
+            files.push(path);
+        }
+    }
+}
+
+/// Format of the syntax dumps, to bump with the syntect dependency which defines it
+const SYNTAX_DUMP_FORMAT: &str = "syntect-3.0";
+
+/// return a hash of the names, sizes and modification times of the syntax files
+/// The dump format is part of it, so that a newer syntect doesn't load an old cache.
+fn syntaxes_key(files: &mut Vec<PathBuf>) -> u64 {
+    files.sort();
+    let mut key = SYNTAX_DUMP_FORMAT.to_owned();
+    for file in files.iter() {
+        let metadata = fs::metadata(file).ok();
+        let len = metadata.as_ref().map_or(0, |m| m.len());
+        let modified = metadata
+            .and_then(|m| m.modified().ok())
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos());
+        key.push_str(&format!("\n{}:{}:{}", file.display(), len, modified));
+    }
+    hash_bytes(key.as_bytes())
+}
+
+/// number of lines searched for a modeline, at the start and at the end of a file
+const MODELINE_LINES: usize = 5;
+
+/// detect the syntax of a buffer, trying in order the syntax chosen by the user, its file name,
+/// its first line or shebang, and Emacs or Vim modelines
+pub fn detect_syntax(b: &Buffer) -> Option<&'static SyntaxReference> {
+    if let Some(syntax) = b.get_syntax().and_then(|name| SYNTAXSET.find_syntax_by_name(name)) {
+        return Some(syntax);
+    }
+    if let Some(syntax) = b.get_filename().and_then(find_syntax) {
+        return Some(syntax);
+    }
+    let first_line = head(b, 0);
+    SYNTAXSET
+        .find_syntax_by_first_line(&first_line)
+        .or_else(|| shebang_syntax(&first_line))
+        .or_else(|| modeline_syntax(b))
+}
+
+/// return the start of a line, long enough for the detection
+fn head(b: &Buffer, line: usize) -> String {
+    b.line(line).chars().take(1024).collect()
+}
+
+/// return the syntax of a file: the one mapped to its name or extension by the `syntaxMapping` setting,
+/// or the one declaring its name or extension
+fn find_syntax(filename: &Path) -> Option<&'static SyntaxReference> {
+    let name = filename.file_name()?.to_str()?;
+    let extension = filename.extension().and_then(|e| e.to_str());
+
+    let mapping = SETTINGS
+        .read()
+        .unwrap()
+        .get::<HashMap<String, String>>("syntaxMapping")
+        .unwrap_or_default();
+    let mapped = |key: &str| mapping.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v);
+    if let Some(syntax) = mapped(name).or_else(|| extension.and_then(mapped)) {
+        match SYNTAXSET.find_syntax_by_name(syntax) {
+            Some(syntax) => return Some(syntax),
+            None => eprintln!("Unknown syntax in syntaxMapping: {}", syntax),
+        }
+    }
+
+    // some syntaxes declare file names, as Makefile
+    SYNTAXSET
+        .find_syntax_by_extension(name)
+        .or_else(|| extension.and_then(|e| SYNTAXSET.find_syntax_by_extension(e)))
+}
+
+/// return the syntax of the interpreter of a shebang line, as `#!/usr/bin/env python3`
+fn shebang_syntax(line: &str) -> Option<&'static SyntaxReference> {
+    if !line.starts_with("#!") {
+        return None;
+    }
+    let mut words = line[2..].split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = words.find(|w| !w.starts_with('-') && !w.contains('='))?;
+    }
+    let name = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    SYNTAXSET.find_syntax_by_token(name)
+}
+
+/// return the syntax named by a modeline in the first or the last lines of the buffer
+fn modeline_syntax(b: &Buffer) -> Option<&'static SyntaxReference> {
+    let len = b.len_lines();
+    let last = max(len.saturating_sub(MODELINE_LINES), MODELINE_LINES);
+    (0..min(MODELINE_LINES, len))
+        .chain(last..len)
+        .filter_map(|i| modeline_language(&head(b, i)).and_then(|l| SYNTAXSET.find_syntax_by_token(l)))
+        .next()
+}
+
+/// return the language of an Emacs (`-*- mode: python -*-`) or Vim (`vim: set ft=python:`) modeline
+fn modeline_language(line: &str) -> Option<&str> {
+    if let Some(start) = line.find("-*-") {
+        let rest = &line[start + 3..];
+        let content = rest[..rest.find("-*-")?].trim();
+        if !content.contains(':') {
+            return Some(content);
+        }
+        return content
+            .split(';')
+            .map(|var| var.splitn(2, ':').map(str::trim).collect::<Vec<_>>())
+            .find(|var| var.len() == 2 && var[0].eq_ignore_ascii_case("mode"))
+            .map(|var| var[1]);
+    }
+    for marker in &["vim:", "vi:", "ex:"] {
+        let i = match line.find(marker) {
+            Some(i) if i == 0 || line[..i].ends_with(char::is_whitespace) => i,
+            _ => continue,
+        };
+        for option in line[i + marker.len()..].split(|c: char| c == ':' || c.is_whitespace()) {
+            let mut option = option.splitn(2, '=');
+            match option.next() {
+                Some("ft") | Some("filetype") | Some("syn") | Some("syntax") => return option.next(),
+                _ => (),
+            }
+        }
+    }
+    None
+}
+
+/// return the names of the available syntaxes, sorted
+pub fn syntax_names() -> Vec<String> {
+    let mut names: Vec<String> = SYNTAXSET.syntaxes().iter().map(|s| s.name.clone()).collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// return the active theme
+pub fn theme() -> &'static Theme {
+    STYLE.read().unwrap().theme
+}
+
+/// return the names of the available themes, sorted
+pub fn theme_names() -> Vec<String> {
+    THEMESET.themes.keys().cloned().collect()
 }
 
 #[derive(Debug)]
 pub struct Styling<'a> {
     pub theme: &'a Theme,
+    pub name: String,
 }
 
 impl<'a> Styling<'a> {
+    /// use the theme named by the `theme` setting
     pub fn new() -> Self {
-        Styling {
-            theme: &THEMESET.themes["Solarized (dark)"],
+        let mut styling = Styling {
+            theme: &THEMESET.themes[DEFAULT_THEME],
+            name: DEFAULT_THEME.to_owned(),
+        };
+        if let Ok(name) = SETTINGS.read().unwrap().get::<String>("theme") {
+            if !styling.set_theme(&name) {
+                eprintln!("Unknown theme: {}", name);
+            }
+        }
+        styling
+    }
+
+    /// switch to the given theme, return false if there is none with this name
+    pub fn set_theme(&mut self, name: &str) -> bool {
+        match THEMESET.themes.get(name) {
+            Some(theme) => {
+                self.theme = theme;
+                self.name = name.to_owned();
+                true
+            }
+            None => false,
         }
     }
 }
@@ -94,57 +345,404 @@ impl<'a> Iterator for StyledLineIterator<'a> {
     }
 }
 
+/// parser state at the end of a line
+type LineState = (ParseState, HighlightState);
+
+/// The styles of the lines, shared with the highlighting thread
 #[derive(Debug)]
-pub struct StylingCache<'a> {
-    // one state by line
-    pub syntax: &'a SyntaxReference,
-    state: Vec<(ParseState, HighlightState)>,
-    pub result: Vec<StyledLine>,
+struct Shared {
+    lines: Vec<StyledLine>,
+    // incremented by each edit, a line parsed before is dropped
+    generation: usize,
+    // first line stored since the view last looked
+    highlighted: Option<usize>,
 }
 
-impl<'a> StylingCache<'a> {
-    pub fn new(syntax: &'a SyntaxReference) -> StylingCache<'a> {
-        StylingCache {
-            syntax,
-            state: Vec::new(),
-            result: Vec::new(),
-        }
-    }
-    pub fn update(&mut self, r: Range<usize>, b: &Buffer) {
-        use std::cmp::min;
-        let start = min(self.state.len(), r.start);
-        let length = r.end - start;
-        self.state.truncate(start);
-        self.result.truncate(start);
-        for line in b.lines().skip(start).take(length + 1) {
-            let highlighter = Highlighter::new(STYLE.theme);
-            let mut state = self
-                .state
-                .last()
-                .unwrap_or(&(
+/// An edit of the lines, replayed by the highlighting thread on its parser states
+#[derive(Debug, Clone)]
+enum Change {
+    Edit(usize, usize, usize),
+    Update(Range<usize>),
+}
+
+/// A request to the highlighting thread: apply the changes and parse the stale lines of the text
+#[derive(Debug)]
+struct Job {
+    generation: usize,
+    text: Rope,
+    changes: Vec<Change>,
+    shared: Arc<Mutex<Shared>>,
+}
+
+/// The parser of the highlighting thread, with its state at the end of each line
+#[derive(Debug)]
+struct Parser {
+    syntax: &'static SyntaxReference,
+    state: Vec<LineState>,
+    // the lines from this one may be stale
+    valid: usize,
+    // re-parsing can't stop before the end of the modified lines
+    edited: usize,
+    // number of lines of the text when it was highlighted
+    lines: usize,
+}
+
+impl Parser {
+    fn apply(&mut self, change: Change) {
+        match change {
+            Change::Edit(start, removed, inserted) => self.edit(start, removed, inserted),
+            Change::Update(r) => {
+                self.valid = min(self.valid, r.start);
+                self.edited = max(self.edited, r.end);
+            }
+        }
+    }
+
+    /// shift the states after an edit replacing `removed` lines from `start` by `inserted` lines
+    fn edit(&mut self, start: usize, removed: usize, inserted: usize) {
+        if start < self.state.len() {
+            let end = min(start + removed, self.state.len());
+            // placeholders, they are parsed again before being used
+            let filler = self.state[start].clone();
+            self.state.splice(start..end, repeat(filler).take(inserted));
+        }
+        // a pending edit after this one is shifted
+        if self.edited != usize::max_value() && self.edited > start + removed {
+            self.edited = self.edited + inserted - removed;
+        }
+        self.edited = max(self.edited, start + inserted + 1);
+        self.valid = min(self.valid, start);
+        self.lines = (self.lines + inserted).saturating_sub(removed);
+    }
+
+    /// parse the stale lines of the job, until the text is edited again
+    /// Re-parsing stops at the first line after the modified ones whose state didn't change.
+    fn run(&mut self, job: Job) {
+        for change in job.changes {
+            self.apply(change);
+        }
+        let len = job.text.len_lines();
+        if len != self.lines {
+            // the cached lines are shifted, nothing after the edit can be trusted
+            self.edited = usize::max_value();
+            self.lines = len;
+        }
+        let highlighter = Highlighter::new(theme());
+        while self.valid < len {
+            let i = self.valid;
+            let mut state = match i {
+                0 => (
                     ParseState::new(self.syntax),
                     HighlightState::new(&highlighter, ScopeStack::new()),
-                )).clone();
+                ),
+                _ => self.state[i - 1].clone(),
+            };
 
-            let l = line.to_string();
+            let l = job.text.line(i).to_string();
             let v = state.0.parse_line(&l, &SYNTAXSET);
-            // let r = HighlightIterator::new(&mut state.1, &v[..], &l, &highlighter)
-            //         .map(|x| StyleSpan{style: x.0, len: x.1.chars().count()})
-            //         .collect();
             let r = HighlightIterator::new(&mut state.1, &v[..], &l, &highlighter)
                 .map(|x| StyleSpan {
                     style: x.0,
                     len: x.1.chars().count(),
                 }).collect();
-            self.result.push(r);
 
-            self.state.push(state);
+            {
+                let mut shared = job.shared.lock().unwrap();
+                if shared.generation != job.generation {
+                    // the next job has the edit
+                    return;
+                }
+                if i < shared.lines.len() {
+                    shared.lines[i] = r;
+                } else {
+                    shared.lines.push(r);
+                }
+                shared.highlighted = Some(shared.highlighted.map_or(i, |h| min(h, i)));
+            }
+
+            let same_state = self.state.get(i) == Some(&state);
+            if i < self.state.len() {
+                self.state[i] = state;
+            } else {
+                self.state.push(state);
+            }
+            self.valid += 1;
+
+            if same_state && self.valid >= self.edited {
+                // the next lines would be parsed exactly as before
+                self.valid = self.state.len();
+                self.edited = 0;
+            }
+        }
+        let mut shared = job.shared.lock().unwrap();
+        if shared.generation == job.generation {
+            self.state.truncate(len);
+            shared.lines.truncate(len);
+            self.valid = len;
+            self.edited = 0;
         }
     }
-    pub fn expand(&mut self, end: usize, b: &Buffer) {
-        let start = self.state.len();
-        if end > start {
-            self.update(start..end, b);
+}
+
+/// run the jobs sent by a styling cache, until it is dropped
+/// Only the last one of the waiting jobs is parsed, with the changes of the others.
+fn highlight_thread(syntax: &'static SyntaxReference, jobs: Receiver<Job>) {
+    let mut parser = Parser {
+        syntax,
+        state: Vec::new(),
+        valid: 0,
+        edited: 0,
+        lines: 0,
+    };
+    while let Ok(mut job) = jobs.recv() {
+        while let Ok(mut next) = jobs.try_recv() {
+            job.changes.append(&mut next.changes);
+            next.changes = job.changes;
+            job = next;
+        }
+        parser.run(job);
+    }
+}
+
+/// The styles of the lines, locked while they are used
+#[derive(Debug)]
+pub struct Styles<'a>(MutexGuard<'a, Shared>);
+
+impl<'a> Deref for Styles<'a> {
+    type Target = Vec<StyledLine>;
+
+    fn deref(&self) -> &Vec<StyledLine> {
+        &self.0.lines
+    }
+}
+
+/// The styles of the lines of a buffer, computed by a thread
+#[derive(Debug)]
+pub struct StylingCache<'a> {
+    pub syntax: &'a SyntaxReference,
+    shared: Arc<Mutex<Shared>>,
+    // the changes not sent to the thread yet
+    changes: Vec<Change>,
+    // the text changed since the last job
+    dirty: bool,
+    jobs: Sender<Job>,
+}
+
+impl<'a> StylingCache<'a> {
+    /// start the thread highlighting the lines in the given syntax
+    pub fn new(syntax: &'static SyntaxReference) -> StylingCache<'a> {
+        let (jobs, receiver) = channel();
+        thread::spawn(move || highlight_thread(syntax, receiver));
+        StylingCache {
+            syntax,
+            shared: Arc::new(Mutex::new(Shared {
+                lines: Vec::new(),
+                generation: 0,
+                highlighted: None,
+            })),
+            changes: Vec::new(),
+            dirty: true,
+            jobs,
+        }
+    }
+
+    /// return the styles of the lines highlighted so far
+    pub fn lines(&self) -> Styles<'_> {
+        Styles(self.shared.lock().unwrap())
+    }
+
+    /// record a change for the thread, the lines it is parsing are outdated
+    fn change(&mut self, shared: &mut Shared, change: Change) {
+        shared.generation += 1;
+        self.changes.push(change);
+        self.dirty = true;
+    }
+
+    /// mark the given lines as modified, their styles are recomputed by the thread
+    pub fn update(&mut self, r: Range<usize>) {
+        let shared = Arc::clone(&self.shared);
+        self.change(&mut shared.lock().unwrap(), Change::Update(r));
+    }
+
+    /// shift the cache after an edit replacing `removed` lines from `start` by `inserted` lines
+    /// The new lines are highlighted by the thread, which can stop at the first untouched line
+    /// whose state didn't change.
+    pub fn edit(&mut self, start: usize, removed: usize, inserted: usize) {
+        if removed == 0 && inserted == 0 {
+            return;
+        }
+        let shared = Arc::clone(&self.shared);
+        let mut shared = shared.lock().unwrap();
+        if start < shared.lines.len() {
+            let end = min(start + removed, shared.lines.len());
+            shared.lines.splice(start..end, (0..inserted).map(|_| StyledLine::new()));
         }
+        self.change(&mut shared, Change::Edit(start, removed, inserted));
+    }
+
+    /// send the text to the thread if it changed, and collect the lines highlighted since the last call
+    /// Return true if lines before `visible` were highlighted.
+    pub fn highlight(&mut self, b: &Buffer, visible: usize) -> bool {
+        let mut shared = self.shared.lock().unwrap();
+        if self.dirty {
+            let job = Job {
+                generation: shared.generation,
+                text: b.snapshot(),
+                changes: self.changes.split_off(0),
+                shared: Arc::clone(&self.shared),
+            };
+            // the thread only ends with the cache
+            let _ = self.jobs.send(job);
+            self.dirty = false;
+        }
+        shared.highlighted.take().map_or(false, |line| line < visible)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const TEXT: &str = "fn main() {\n    let a = \"text\";\n    // comment\n    let b = 1;\n}\n";
+
+    fn buffer(text: &str) -> Buffer {
+        let mut b = Buffer::new();
+        b.insert(0, text);
+        b
+    }
+
+    fn styles(cache: &StylingCache<'_>) -> Vec<Vec<Style>> {
+        cache.lines().iter().map(|l| l.iter().collect()).collect()
+    }
+
+    /// send the text to the thread, and wait for the end of the job which holds the shared lines
+    fn highlight(cache: &mut StylingCache<'_>, b: &Buffer) {
+        cache.highlight(b, 10);
+        while Arc::strong_count(&cache.shared) > 1 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn check(cache: &mut StylingCache<'static>, b: &Buffer) {
+        highlight(cache, b);
+        let mut fresh = StylingCache::new(cache.syntax);
+        highlight(&mut fresh, b);
+        assert_eq!(styles(cache), styles(&fresh));
+    }
+
+    /// replace the given chars, shifting the cache like the view does
+    fn edit(cache: &mut StylingCache<'_>, b: &mut Buffer, r: Range<usize>, text: &str) {
+        let lines = b.char_to_line(r.start)..b.char_to_line(r.end) + 1;
+        let len = b.len_lines();
+        b.remove(r.clone());
+        b.insert(r.start, text);
+        let removed = lines.end - lines.start;
+        cache.edit(lines.start, removed, removed + b.len_lines() - len);
+    }
+
+    fn setup() -> (Buffer, StylingCache<'static>) {
+        let b = buffer(&TEXT.repeat(20));
+        let mut cache = StylingCache::new(SYNTAXSET.find_syntax_by_extension("rs").unwrap());
+        highlight(&mut cache, &b);
+        (b, cache)
+    }
+
+    #[test]
+    fn paste() {
+        let (mut b, mut cache) = setup();
+        // lines are shifted
+        let idx = b.line_to_char(3);
+        edit(&mut cache, &mut b, idx..idx, &TEXT.repeat(3));
+        check(&mut cache, &b);
+
+        // an unclosed comment changes every line after the viewport
+        let idx = b.line_to_char(5);
+        edit(&mut cache, &mut b, idx..idx, "/*\nlet x = 1;\n");
+        check(&mut cache, &b);
+
+        let end = b.line_to_char(6);
+        edit(&mut cache, &mut b, idx..end, "");
+        check(&mut cache, &b);
+    }
+
+    #[test]
+    fn delete_across_viewport() {
+        let (mut b, mut cache) = setup();
+        let (start, end) = (b.line_to_char(7) + 4, b.line_to_char(52) + 2);
+        edit(&mut cache, &mut b, start..end, "");
+        check(&mut cache, &b);
+
+        // the first line of the buffer
+        let end = b.line_to_char(2);
+        edit(&mut cache, &mut b, 0..end, "");
+        check(&mut cache, &b);
+    }
+
+    #[test]
+    fn user_syntaxes() {
+        let dir = std::env::temp_dir().join(format!("nonedit-syntaxes-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("dsl")).unwrap();
+        fs::write(
+            dir.join("dsl").join("Dsl.sublime-syntax"),
+            "%YAML 1.2\n---\nname: Dsl\nfile_extensions: [dsl]\nscope: source.dsl\n\
+             contexts:\n  main:\n    - match: '\\blet\\b'\n      scope: keyword.dsl\n",
+        )
+        .unwrap();
+
+        let mut files = Vec::new();
+        syntax_files(&dir, &mut files);
+        assert_eq!(files.len(), 1);
+        let key = syntaxes_key(&mut files);
+
+        let cache = dir.join("cache").join(format!("syntaxes-{:016x}.packdump", key));
+        let syntaxes = build_syntaxes(&dir, &cache);
+        assert_eq!(syntaxes.find_syntax_by_extension("dsl").unwrap().name, "Dsl");
+        assert!(syntaxes.find_syntax_by_extension("rs").is_some());
+
+        let cached: SyntaxSet = from_dump_file(&cache).unwrap();
+        assert!(cached.find_syntax_by_name("Dsl").is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn detect(filename: Option<&str>, text: &str) -> String {
+        let mut b = buffer(text);
+        if let Some(filename) = filename {
+            b.set_filename(Path::new(filename));
+        }
+        detect_syntax(&b).map_or("".to_owned(), |s| s.name.clone())
+    }
+
+    #[test]
+    fn detection() {
+        assert_eq!(detect(Some("/src/Makefile"), ""), "Makefile");
+        assert_eq!(detect(Some("main.rs"), "#!/bin/sh\n"), "Rust");
+        assert_eq!(detect(Some("run"), "#!/usr/bin/env python3\nprint()\n"), "Python");
+        assert_eq!(detect(None, "#!/usr/bin/ruby -w\n"), "Ruby");
+        assert_eq!(detect(None, "<?xml version=\"1.0\"?>\n<a/>\n"), "XML");
+        assert_eq!(detect(None, "// -*- mode: C++; tab-width: 4 -*-\n"), "C++");
+        assert_eq!(detect(None, &format!("{}# vim: set ts=4 ft=python:\n", "\n".repeat(20))), "Python");
+        assert_eq!(detect(None, "text\n"), "");
+
+        let mut b = buffer("fn main() {}\n");
+        b.set_filename(Path::new("main.rs"));
+        b.set_syntax(Some("Python".to_owned()));
+        assert_eq!(detect_syntax(&b).unwrap().name, "Python");
+    }
+
+    #[test]
+    fn undo() {
+        let (mut b, mut cache) = setup();
+        let previous = b.clone();
+        let idx = b.line_to_char(4);
+        edit(&mut cache, &mut b, idx..idx + 3, "\"\n\n");
+        check(&mut cache, &b);
+
+        let (lines, inserted) = b.changed_lines(&previous);
+        assert_eq!((lines.clone(), inserted), (4..7, 2));
+        cache.edit(lines.start, lines.end - lines.start, inserted);
+        b.restore(previous);
+        check(&mut cache, &b);
     }
 }
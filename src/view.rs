@@ -3,34 +3,25 @@ use std::io;
 use std::ops::Range;
 use std::rc::Rc;
 
+use encoding::EncodingRef;
+
 use crate::styling::SYNTAXSET;
 
 use syntect::highlighting;
+use syntect::highlighting::FontStyle;
 
-use crate::buffer::Buffer;
-use crate::cursor::Cursor;
+use crate::buffer::{Buffer, Indentation, LineFeed};
+use crate::cursor::{grapheme_width, next_col, Cursor};
 use crate::keybinding::KeyBinding;
+use crate::session::ViewSession;
+use crate::shell;
+use crate::styling;
 use crate::styling::StylingCache;
-use crate::styling::STYLE;
 use crate::window::Geometry;
-use crate::SETTINGS;
 
-use crate::nanovg::Canvas;
+use crate::nanovg::{Canvas, FontFace, TextStyle};
 use nanovg::Color;
 
-#[derive(Debug, Clone, Copy)]
-pub enum Indentation {
-    Tab(u32),
-    Space(u32),
-}
-
-#[derive(Debug, Clone, Copy)]
-pub enum LineFeed {
-    CR,
-    LF,
-    CRLF,
-}
-
 #[derive(Debug, Clone, Copy)]
 pub enum Direction {
     Up,
@@ -193,10 +184,10 @@ pub struct View<'a> {
     cursor: Cursor,
     selection: Option<Selection>,
     undo_stack: UndoStack,
-    linefeed: LineFeed,
     geometry: Geometry,
     viewport: Viewport,
     styling: Option<StylingCache<'a>>,
+    message: Option<String>,
 }
 
 impl<'a> View<'a> {
@@ -207,18 +198,18 @@ impl<'a> View<'a> {
             cursor: Cursor::new(buffer.clone()),
             selection: None,
             undo_stack: UndoStack::new(),
-            linefeed: LineFeed::LF,
             geometry,
             viewport: Viewport::default(),
             styling: None,
+            message: None,
         };
         v.relayout(geometry);
-        v.detect_linefeed();
         v
     }
 
     /// save the underlying buffer to disk
     pub fn save(&mut self) -> io::Result<()> {
+        let state = self.get_state();
         {
             let mut b = self.buffer.borrow_mut();
             if b.get_filename().is_some() {
@@ -230,10 +221,87 @@ impl<'a> View<'a> {
                 }
             }
         }
+        // saving may have trimmed the buffer, which can be undone
+        self.cursor.set_index(self.cursor.get_index());
+        self.clear_selection();
         self.detect_syntax();
+        if self.buffer.borrow().get_revision() != state.buffer.get_revision() {
+            self.undo_stack.push(&state);
+            self.invalidate_styling_cache();
+        }
+        Ok(())
+    }
+
+    /// save the underlying buffer to disk with the given encoding
+    /// The previous encoding is kept if the buffer can't be saved
+    pub fn save_with_encoding(&mut self, encoding: EncodingRef) -> io::Result<()> {
+        let previous = self.buffer.borrow().get_encoding();
+        self.buffer.borrow_mut().set_encoding(encoding);
+        let result = self.save();
+        if result.is_err() {
+            self.buffer.borrow_mut().set_encoding(previous);
+        }
+        result
+    }
+
+    /// reload the underlying file, decoding it with the given encoding
+    pub fn reopen_with_encoding(&mut self, encoding: EncodingRef) -> io::Result<()> {
+        let state = self.get_state();
+        self.buffer.borrow_mut().reload_with_encoding(encoding)?;
+        self.undo_stack.push(&state);
+        self.refresh();
         Ok(())
     }
 
+    /// set the message displayed in the status bar
+    pub fn set_message<S: Into<String>>(&mut self, message: S) {
+        self.message = Some(message.into());
+    }
+
+    /// get the message displayed in the status bar
+    pub fn get_message(&self) -> Option<&str> {
+        self.message.as_ref().map(|m| m.as_str())
+    }
+
+    /// remove the message displayed in the status bar
+    pub fn clear_message(&mut self) {
+        self.message = None;
+    }
+
+    /// return the state of the view to save in the session
+    pub fn session(&self) -> ViewSession {
+        let b = self.buffer.borrow();
+        let filename = b.get_filename().map(|f| f.to_owned());
+        ViewSession {
+            content: if filename.is_none() && b.len_chars() > 0 {
+                Some(b.to_string())
+            } else {
+                None
+            },
+            filename,
+            cursor: self.cursor.get_index(),
+            selection: self.selection.map(|s| (s.start, s.end)),
+            line_start: self.viewport.line_start,
+            col_start: self.viewport.col_start,
+        }
+    }
+
+    /// restore the state of the view saved in the session
+    /// Positions are clamped, as the file could have changed in between.
+    pub fn restore_session(&mut self, session: &ViewSession) {
+        if let Some(ref content) = session.content {
+            self.insert(content);
+        }
+        let len = self.buffer.borrow().len_chars();
+        let len_lines = self.buffer.borrow().len_lines();
+        self.cursor.set_index(session.cursor);
+        self.selection = session
+            .selection
+            .map(|(start, end)| Selection::new(std::cmp::min(start, len), std::cmp::min(end, len)));
+        self.viewport.line_start = std::cmp::min(session.line_start, len_lines - 1);
+        self.viewport.col_start = session.col_start;
+    }
+
     /// return the number of line visible on screen
     pub fn page_length(&self) -> usize {
         self.viewport.heigth
@@ -244,8 +312,6 @@ impl<'a> View<'a> {
         self.geometry = geometry;
         self.viewport.heigth = (self.geometry.h / self.geometry.font_height) as usize - 1;
         self.viewport.width = (self.geometry.w / self.geometry.font_advance) as usize - 1;
-        let end = self.viewport.line_end();
-        self.expand_styling_cache(end);
     }
 
     fn get_state(&self) -> State {
@@ -270,16 +336,19 @@ impl<'a> View<'a> {
             .map(|x| x.to_string())
     }
 
-    /// detect language from extension
+    /// detect language from the file name and content
     pub fn detect_syntax(&mut self) {
-        let plain_text = SYNTAXSET.find_syntax_plain_text();
-        let syntax = match self.get_extension() {
-            None => plain_text,
-            Some(ext) => SYNTAXSET.find_syntax_by_extension(&ext).unwrap_or(plain_text),
-        };
-        self.styling = Some(StylingCache::new(syntax));
-        let end = self.buffer.borrow().len_lines(); // self.viewport.line_end();
-        self.expand_styling_cache(end);
+        // highlighting a large file would take too long
+        if self.buffer.borrow().is_large() {
+            self.styling = None;
+            return;
+        }
+        let syntax = styling::detect_syntax(&self.buffer.borrow()).unwrap_or_else(|| SYNTAXSET.find_syntax_plain_text());
+        // keep the highlighted lines when the syntax is the same
+        match self.styling {
+            Some(ref style) if style.syntax.name == syntax.name => (),
+            _ => self.styling = Some(StylingCache::new(syntax)),
+        }
     }
 
     /// get the current syntax
@@ -290,19 +359,99 @@ impl<'a> View<'a> {
         }
     }
 
+    /// return true if the buffer has unsaved modifications
+    pub fn is_dirty(&self) -> bool {
+        self.buffer.borrow().is_dirty()
+    }
+
+    /// return true if the view displays the given buffer
+    pub fn is_on_buffer(&self, buffer: &Rc<RefCell<Buffer>>) -> bool {
+        Rc::ptr_eq(&self.buffer, buffer)
+    }
+
+    /// return the file name of the buffer, or `untitled`
+    pub fn get_title(&self) -> String {
+        match self.buffer.borrow().get_filename().and_then(|f| f.file_name()) {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => "untitled".to_owned(),
+        }
+    }
+
     /// get the buffer encoding
     pub fn get_encoding(&self) -> &str {
         self.buffer.borrow().get_encoding().name()
     }
 
-    fn update_styling_cache(&mut self, r: Range<usize>) {
+    /// return true if the file starts with a byte order mark
+    pub fn has_bom(&self) -> bool {
+        self.buffer.borrow().has_bom()
+    }
+
+    /// add or remove the byte order mark of the file
+    pub fn set_bom(&mut self, has_bom: bool) -> io::Result<()> {
+        if !self.check_writable() {
+            return Ok(());
+        }
+        let state = self.get_state();
+        self.buffer.borrow_mut().set_bom(has_bom)?;
+        self.undo_stack.push(&state);
+        Ok(())
+    }
+
+    /// return the lines touched by an edit of the given chars, and the line count before the edit
+    fn edited_lines(&self, r: Range<usize>) -> (Range<usize>, usize) {
+        let b = self.buffer.borrow();
+        let end = std::cmp::min(r.end, b.len_chars());
+        (b.char_to_line(r.start)..b.char_to_line(end) + 1, b.len_lines())
+    }
+
+    /// return the chars removed by an edit at the cursor: the selection, or `before` and `after` chars around the cursor
+    fn edited_chars(&self, before: usize, after: usize) -> Range<usize> {
+        match self.selection {
+            Some(r) => r.into(),
+            None => {
+                let idx = self.cursor.get_index();
+                idx.saturating_sub(before)..idx + after
+            }
+        }
+    }
+
+    /// shift the styling cache after an edit
+    /// `lines` are the lines touched by the edit and `len_lines` the line count, both before the edit.
+    fn update_styling_cache(&mut self, (lines, len_lines): (Range<usize>, usize)) {
+        let len = self.buffer.borrow().len_lines();
         if let Some(ref mut style) = self.styling {
-            style.update(r, &self.buffer.borrow());
+            let removed = lines.end - lines.start;
+            style.edit(lines.start, removed, (removed + len).saturating_sub(len_lines));
         }
     }
-    fn expand_styling_cache(&mut self, end: usize) {
+
+    /// recompute the styles of the whole buffer
+    pub fn invalidate_styling_cache(&mut self) {
         if let Some(ref mut style) = self.styling {
-            style.expand(end, &self.buffer.borrow());
+            style.update(0..usize::max_value());
+        }
+    }
+
+    /// restore a state of the undo stack, the styles of the lines which differ are recomputed
+    fn restore_state(&mut self, state: State) {
+        let edited = {
+            let b = self.buffer.borrow();
+            let (lines, _) = b.changed_lines(&state.buffer);
+            (lines, b.len_lines())
+        };
+        self.buffer.borrow_mut().restore(state.buffer);
+        self.cursor = state.cursor;
+        self.update_styling_cache(edited);
+    }
+
+    /// send the edited text to the highlighting thread, and collect the lines it highlighted
+    /// Return true if visible lines were highlighted.
+    pub fn highlight(&mut self) -> bool {
+        let b = self.buffer.borrow();
+        match self.styling {
+            Some(ref mut style) => style.highlight(&b, self.viewport.line_end() + 1),
+            None => false,
         }
     }
 
@@ -330,9 +479,35 @@ impl<'a> View<'a> {
     //     self.update_styling_cache(start..end);
     // }
 
+    /// return true if the buffer must not be modified
+    pub fn is_read_only(&self) -> bool {
+        self.buffer.borrow().is_read_only()
+    }
+
+    /// forbid or allow the modifications of the buffer
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.buffer.borrow_mut().set_read_only(read_only);
+    }
+
+    /// return true if the buffer can be modified, show a message otherwise
+    fn check_writable(&mut self) -> bool {
+        if self.buffer.borrow().is_loading() {
+            self.set_message("The buffer is still loading");
+            return false;
+        }
+        if self.buffer.borrow().is_read_only() {
+            self.set_message("The buffer is read-only");
+            return false;
+        }
+        true
+    }
+
     /// insert the given char at the cursor position
     pub fn insert_char(&mut self, ch: char) {
-        let start = self.line_idx();
+        if !self.check_writable() {
+            return;
+        }
+        let edited = self.edited_lines(self.edited_chars(0, 0));
         self.push_state();
 
         if let Some(r) = self.selection {
@@ -345,12 +520,12 @@ impl<'a> View<'a> {
         self.clear_selection();
         self.focus_on_cursor();
 
-        let end = self.viewport.line_end();
-        self.update_styling_cache(start..end);
+        self.update_styling_cache(edited);
     }
 
     pub fn insert_linefeed(&mut self) {
-        match self.linefeed {
+        let linefeed = self.get_linefeed();
+        match linefeed {
             LineFeed::CRLF => self.insert("\r\n"),
             LineFeed::CR => self.insert_char('\r'),
             LineFeed::LF => self.insert_char('\n'),
@@ -359,7 +534,10 @@ impl<'a> View<'a> {
 
     /// insert the given string at the cursor position
     pub fn insert(&mut self, text: &str) {
-        let start = self.line_idx();
+        if !self.check_writable() {
+            return;
+        }
+        let edited = self.edited_lines(self.edited_chars(0, 0));
         self.push_state();
 
         if let Some(r) = self.selection {
@@ -371,13 +549,88 @@ impl<'a> View<'a> {
         self.clear_selection();
         self.focus_on_cursor();
 
-        let end = self.viewport.line_end();
-        self.update_styling_cache(start..end);
+        self.update_styling_cache(edited);
+    }
+
+    /// replace the whole content of the buffer, as a single undo step
+    pub fn replace_content(&mut self, text: &str) {
+        if !self.check_writable() {
+            return;
+        }
+        let edited = self.edited_lines(0..self.buffer.borrow().len_chars());
+        self.push_state();
+        {
+            let mut b = self.buffer.borrow_mut();
+            let len = b.len_chars();
+            b.remove(0..len);
+            b.insert(0, text);
+        }
+        self.cursor.set_index(self.cursor.get_index());
+        self.clear_selection();
+        self.focus_on_cursor();
+        self.update_styling_cache(edited);
+    }
+
+    /// pipe the selection, or the whole buffer, through a shell command and replace it with the output
+    /// Nothing is replaced if the command fails, its error output is shown as message.
+    pub fn filter(&mut self, command: &str) {
+        if !self.check_writable() {
+            return;
+        }
+        let range: Range<usize> = match self.selection {
+            Some(r) => r.into(),
+            None => 0..self.buffer.borrow().len_chars(),
+        };
+        let input = self.buffer.borrow().slice(range.clone());
+        let output = match shell::filter(command, &input) {
+            Ok(output) => output,
+            Err(e) => {
+                self.set_message(format!("{}: {}", command, e));
+                return;
+            }
+        };
+        let stderr = output.stderr.trim();
+        if !output.success() {
+            let status = match output.code {
+                Some(code) => format!("exited with code {}", code),
+                None => "was killed".to_owned(),
+            };
+            if stderr.is_empty() {
+                self.set_message(format!("{} {}", command, status));
+            } else {
+                self.set_message(format!("{} {}: {}", command, status, stderr));
+            }
+            return;
+        }
+        if !stderr.is_empty() {
+            self.set_message(stderr.to_owned());
+        }
+
+        let edited = self.edited_lines(range.clone());
+        self.push_state();
+        {
+            let mut b = self.buffer.borrow_mut();
+            b.remove(range.clone());
+            b.insert(range.start, &output.stdout);
+        }
+        if self.selection.is_some() {
+            let end = range.start + output.stdout.chars().count();
+            self.selection = Some(Selection::new(range.start, end));
+            self.cursor.set_index(end);
+        } else {
+            self.cursor.set_index(self.cursor.get_index());
+        }
+        self.focus_on_cursor();
+        self.update_styling_cache(edited);
     }
 
     /// delete the charater directly to the left of cursor
     pub fn backspace(&mut self) {
-        let start = self.line_idx();
+        if !self.check_writable() {
+            return;
+        }
+        // the previous char may be a CRLF
+        let edited = self.edited_lines(self.edited_chars(2, 0));
         self.push_state();
         if let Some(r) = self.selection {
             let mut b = self.buffer.borrow_mut();
@@ -391,13 +644,15 @@ impl<'a> View<'a> {
         self.clear_selection();
         self.focus_on_cursor();
 
-        let end = self.viewport.line_end();
-        self.update_styling_cache(start..end);
+        self.update_styling_cache(edited);
     }
 
     /// delete the charater under the cursor
     pub fn delete_at_cursor(&mut self) {
-        let start = self.line_idx();
+        if !self.check_writable() {
+            return;
+        }
+        let edited = self.edited_lines(self.edited_chars(0, 2));
         self.push_state();
         if let Some(r) = self.selection {
             self.cursor.set_index(r.lower());
@@ -412,8 +667,7 @@ impl<'a> View<'a> {
         }
         self.clear_selection();
         self.focus_on_cursor();
-        let end = self.viewport.line_end();
-        self.update_styling_cache(start..end);
+        self.update_styling_cache(edited);
     }
 
     /// return a newly allocated string of the buffer
@@ -423,31 +677,29 @@ impl<'a> View<'a> {
 
     /// undo the last action
     pub fn undo(&mut self) {
+        if !self.check_writable() {
+            return;
+        }
         if self.undo_stack.is_on_top() && !self.undo_stack.stack.is_empty() {
             // push the current state in case we redo
             let st = self.get_state();
             self.undo_stack.push_only(&st);
         }
         if let Some(state) = self.undo_stack.undo() {
-            self.buffer.replace(state.buffer);
-            self.cursor = state.cursor;
+            self.restore_state(state);
         }
         self.focus_on_cursor();
-        let start = self.line_idx();
-        let end = self.viewport.line_end();
-        self.update_styling_cache(start..end);
     }
 
     /// redo the last undo action
     pub fn redo(&mut self) {
+        if !self.check_writable() {
+            return;
+        }
         if let Some(state) = self.undo_stack.redo() {
-            self.buffer.replace(state.buffer);
-            self.cursor = state.cursor;
+            self.restore_state(state);
         }
         self.focus_on_cursor();
-        let start = self.line_idx();
-        let end = self.viewport.line_end();
-        self.update_styling_cache(start..end);
     }
 
     /// return the currently selection
@@ -545,6 +797,13 @@ impl<'a> View<'a> {
         self.focus_on_cursor();
     }
 
+    /// put the cursor at the begining of the buffer
+    pub fn move_to_start(&mut self) {
+        self.cursor.set_index(0);
+        self.clear_selection();
+        self.focus_on_cursor();
+    }
+
     /// put the cursor at the begining of the line
     pub fn home(&mut self, expand_selection: bool) {
         // let l = self.line_idx();
@@ -647,87 +906,290 @@ impl<'a> View<'a> {
         }
     }
 
-    /// Detect the carriage return type of the buffer
-    pub fn detect_linefeed(&mut self) {
-        #[cfg(target_os = "windows")]
-        let linefeed = LineFeed::CRLF;
-        #[cfg(not(target_os = "windows"))]
-        let linefeed = LineFeed::LF;
+    /// get the indentation used by the buffer
+    pub fn get_indentation(&self) -> Indentation {
+        self.buffer.borrow().get_indentation()
+    }
 
-        let b = self.buffer.borrow();
-        if b.len_chars() == 0 {
-            self.linefeed = linefeed;
+    /// override the indentation used by the buffer
+    pub fn set_indentation(&mut self, indentation: Indentation) {
+        self.buffer.borrow_mut().set_indentation(indentation);
+    }
+
+    /// get the displayed width of a tabulation
+    pub fn get_tab_size(&self) -> usize {
+        self.buffer.borrow().get_tab_size()
+    }
+
+    /// override the displayed width of a tabulation
+    pub fn set_tab_size(&mut self, tab_size: usize) {
+        self.buffer.borrow_mut().set_tab_size(tab_size);
+        self.cursor.set_index(self.cursor.get_index());
+        self.focus_on_cursor();
+    }
+
+    /// re-run the indentation detection on the buffer content
+    pub fn detect_indentation(&mut self) {
+        let indentation = self.buffer.borrow().detect_indentation();
+        self.set_indentation(indentation);
+        self.cursor.set_index(self.cursor.get_index());
+    }
+
+    /// get the line ending used by the buffer
+    pub fn get_linefeed(&self) -> LineFeed {
+        self.buffer.borrow().get_linefeed()
+    }
+
+    /// return true if the buffer contains several kinds of line ending
+    pub fn is_linefeed_mixed(&self) -> bool {
+        self.buffer.borrow().is_linefeed_mixed()
+    }
+
+    /// convert every line ending of the buffer to the given one
+    /// The conversion is a single undo step.
+    pub fn convert_linefeed(&mut self, linefeed: LineFeed) {
+        if !self.check_writable() {
             return;
         }
+        let edited = self.edited_lines(0..self.buffer.borrow().len_chars());
+        self.push_state();
+        let (line, col) = (self.line_idx(), self.col_idx());
+        self.buffer.borrow_mut().convert_linefeed(linefeed);
 
-        let mut cr = 0;
-        let mut lf = 0;
-        let mut crlf = 0;
+        // line count is unchanged, so restore the cursor on the same point
+        self.set_cursor_point(line, col);
+        self.clear_selection();
+        self.focus_on_cursor();
+        self.update_styling_cache(edited);
+    }
 
-        let mut chars = b.chars().take(1000);
-        while let Some(c) = chars.next() {
-            if c == '\r' {
-                if let Some(c2) = chars.next() {
-                    if c2 == '\n' {
-                        crlf += 1;
-                    } else {
-                        cr += 1;
-                    }
+    /// put the cursor on the given line and column, clamped to the buffer
+    fn set_cursor_point(&mut self, line: usize, col: usize) {
+        let line = std::cmp::min(line, self.buffer.borrow().len_lines() - 1);
+        let p = crate::cursor::Point {
+            line,
+            col,
+            buffer: self.buffer.clone(),
+        };
+        let idx: crate::cursor::Index = p.into();
+        self.cursor.set_index(idx.index);
+    }
+
+    /// put the cursor on the given 1-based line and column, and show it
+    pub fn goto(&mut self, line: usize, col: usize) {
+        self.set_cursor_point(line.saturating_sub(1), col.saturating_sub(1));
+        self.clear_selection();
+        self.focus_on_cursor();
+    }
+
+    /// reload the file from disk, keeping the cursor on the same line and column
+    /// The reload can be undone.
+    pub fn reload(&mut self) -> io::Result<()> {
+        let state = self.get_state();
+        let (line, col) = (self.line_idx(), self.col_idx());
+        self.buffer.borrow_mut().reload()?;
+        self.undo_stack.push(&state);
+
+        self.set_cursor_point(line, col);
+        self.refresh();
+        Ok(())
+    }
+
+    /// update the view after its buffer was changed by another view
+    pub fn refresh(&mut self) {
+        self.cursor.set_index(self.cursor.get_index());
+        self.clear_selection();
+        self.focus_on_cursor();
+        self.detect_syntax();
+        self.invalidate_styling_cache();
+    }
+
+    /// return the range of lines covered by the selection, or the cursor line if there is none
+    fn selected_lines(&self) -> Range<usize> {
+        match self.selection {
+            None => self.line_idx()..self.line_idx() + 1,
+            Some(sel) => {
+                let r: Range<usize> = sel.into();
+                let b = self.buffer.borrow();
+                let start = b.char_to_line(r.start);
+                let mut end = b.char_to_line(r.end);
+                // a selection ending on the first char of a line doesn't include it
+                if end > start && b.line_to_char(end) == r.end {
+                    end -= 1;
                 }
-            } else if c == '\n' {
-                lf += 1;
+                start..end + 1
             }
         }
+    }
 
-        self.linefeed = if cr > crlf && cr > lf {
-            LineFeed::CR
-        } else if lf > crlf && lf > cr {
-            LineFeed::LF
-        } else {
-            LineFeed::CRLF
+    /// return true if the selection spans more than one line
+    fn is_multiline_selection(&self) -> bool {
+        match self.selection {
+            None => false,
+            Some(sel) => {
+                let r: Range<usize> = sel.into();
+                let b = self.buffer.borrow();
+                b.char_to_line(r.start) != b.char_to_line(r.end)
+            }
         }
     }
 
-    pub fn detect_indentation(&self) -> Indentation {
-        // detect Tabs first. If the first char of a line is more often a Tab
-        // then we consider the indentation as tabulation.
-        let b = self.buffer.borrow();
-        let mut tab = 0;
-        let mut space = 0;
-        for line in b.lines() {
-            match line.chars().next() {
-                Some(' ') => space += 1,
-                Some('\t') => tab += 1,
-                _ => (),
+    /// return the number of whitespace chars at the begining of the line and their width in columns
+    fn leading_whitespace(&self, line: usize) -> (usize, usize) {
+        let tabsize = self.get_tab_size();
+        let mut count = 0;
+        let mut width = 0;
+        for c in self.buffer.borrow().chars_on_line(line) {
+            match c {
+                ' ' => width += 1,
+                '\t' => width = ((width + tabsize) / tabsize) * tabsize,
+                _ => break,
             }
+            count += 1;
         }
-        if tab > space {
-            let tabsize: u32 = SETTINGS.read().unwrap().get("tabSize").unwrap();
-            return Indentation::Tab(tabsize);
+        (count, width)
+    }
+
+    /// select the given lines entirely, keeping the cursor at the end
+    fn select_lines(&mut self, lines: Range<usize>) {
+        let (start, end) = {
+            let b = self.buffer.borrow();
+            (b.line_to_char(lines.start), b.line_to_last_char(lines.end - 1))
+        };
+        self.cursor.set_index(end);
+        self.selection = Some(Selection::new(start, end));
+    }
+
+    /// indent the selected lines, or insert an indentation at the cursor if the selection is on a single line
+    pub fn indent(&mut self) {
+        if !self.check_writable() {
+            return;
+        }
+        let indentation = self.get_indentation();
+        if !self.is_multiline_selection() {
+            let n = indentation.width();
+            let p = self.col_idx();
+            let text = match indentation {
+                Indentation::Space(_) => " ".repeat(((p + n) / n) * n - p),
+                Indentation::Tab(_) => "\t".to_owned(),
+            };
+            self.insert(&text);
+            return;
         }
 
-        // Algorythm from
-        // https://medium.com/firefox-developer-tools/detecting-code-indentation-eff3ed0fb56b
-        use std::collections::HashMap;
-        let mut indents = HashMap::new();
-        let mut last = 0;
+        let lines = self.selected_lines();
+        let len_lines = self.buffer.borrow().len_lines();
+        self.push_state();
+        let unit = indentation.unit();
+        for line in lines.clone() {
+            if self.buffer.borrow().line_len_no_eol(line) == 0 {
+                continue;
+            }
+            let idx = self.buffer.borrow().line_to_char(line);
+            self.buffer.borrow_mut().insert(idx, &unit);
+        }
+        self.select_lines(lines.clone());
+        self.focus_on_cursor();
+
+        self.update_styling_cache((lines, len_lines));
+    }
 
-        for line in b.lines() {
-            let width = line.chars().take_while(|c| *c == ' ').count();
-            let indent = (width as isize - last as isize).abs();
-            if indent > 1 {
-                let i = indents.entry(indent).or_insert(0);
-                *i += 1;
+    /// remove one level of indentation from the selected lines, or from the cursor line
+    pub fn outdent(&mut self) {
+        if !self.check_writable() {
+            return;
+        }
+        let lines = self.selected_lines();
+        let had_selection = self.selection.is_some();
+        let tabsize = self.get_tab_size();
+        let unit = self.get_indentation().width();
+        let cursor = self.cursor.get_index();
+        let len_lines = self.buffer.borrow().len_lines();
+        let mut removed_on_cursor_line = 0;
+
+        self.push_state();
+        for line in lines.clone() {
+            let mut count = 0;
+            let mut width = 0;
+            for c in self.buffer.borrow().chars_on_line(line) {
+                match c {
+                    ' ' => width += 1,
+                    '\t' => width = ((width + tabsize) / tabsize) * tabsize,
+                    _ => break,
+                }
+                count += 1;
+                if width >= unit {
+                    break;
+                }
+            }
+            if count > 0 {
+                let idx = self.buffer.borrow().line_to_char(line);
+                self.buffer.borrow_mut().remove(idx..idx + count);
+                if line == self.line_idx() {
+                    removed_on_cursor_line = count;
+                }
             }
-            last = width;
         }
-        if let Some(i) = indents.iter().max_by(|x,y| x.1.cmp(y.1)) {
-            println!("largest {}", i.0);
-            Indentation::Space(*i.0 as u32)
+
+        if had_selection {
+            self.select_lines(lines.clone());
         } else {
-            let tabsize: u32 = SETTINGS.read().unwrap().get("tabSize").unwrap();
-            Indentation::Space(tabsize)
+            let line_start = self.buffer.borrow().line_to_char(lines.start);
+            let idx = std::cmp::max(line_start, cursor.saturating_sub(removed_on_cursor_line));
+            self.cursor.set_index(idx);
         }
+        self.focus_on_cursor();
+
+        self.update_styling_cache((lines, len_lines));
+    }
+
+    /// rewrite the leading whitespace of every line from the `from` indentation to the `to` indentation
+    /// The whole operation is a single undo step.
+    pub fn reindent(&mut self, from: Indentation, to: Indentation) {
+        if !self.check_writable() {
+            return;
+        }
+        let edited = self.edited_lines(0..self.buffer.borrow().len_chars());
+        self.push_state();
+        let cursor_line = self.line_idx();
+        let len_lines = self.buffer.borrow().len_lines();
+        for line in 0..len_lines {
+            let (count, width) = self.leading_whitespace(line);
+            if count == 0 {
+                continue;
+            }
+            let level = width / from.width();
+            let rest = width % from.width();
+            let mut ws = to.whitespace(level * to.width());
+            ws.push_str(&" ".repeat(rest));
+
+            let idx = self.buffer.borrow().line_to_char(line);
+            let old = self.buffer.borrow().slice(idx..idx + count);
+            if old != ws {
+                let mut b = self.buffer.borrow_mut();
+                b.remove(idx..idx + count);
+                b.insert(idx, &ws);
+            }
+        }
+        self.set_indentation(to);
+
+        self.clear_selection();
+        let idx = self.buffer.borrow().line_to_char(cursor_line);
+        self.cursor.set_index(idx);
+        self.focus_on_cursor();
+        self.update_styling_cache(edited);
+    }
+
+    /// convert the indentation of the whole buffer to spaces of the given width
+    pub fn convert_indentation_to_spaces(&mut self, width: u32) {
+        let from = self.get_indentation();
+        self.reindent(from, Indentation::Space(width));
+    }
+
+    /// convert the indentation of the whole buffer to tabs of the given width
+    pub fn convert_indentation_to_tabs(&mut self, width: u32) {
+        let from = self.get_indentation();
+        self.reindent(from, Indentation::Tab(width));
     }
 
     /// move the view so that the cursor is visible
@@ -754,9 +1216,6 @@ impl<'a> View<'a> {
         if c > self.viewport.col_end() {
             self.viewport.col_start = c - pagewidth;
         }
-
-        let end = self.viewport.line_end();
-        self.expand_styling_cache(end);
     }
 
     /// Draw the vew on the given screen
@@ -765,7 +1224,9 @@ impl<'a> View<'a> {
         let line_spacing = self.geometry.font_height;
         let mut y = line_spacing;
 
-        let tabsize: i32 = SETTINGS.read().unwrap().get("tabSize").unwrap();
+        let tabsize = self.get_tab_size();
+        let theme = styling::theme();
+        let foreground = theme.settings.foreground.unwrap_or(highlighting::Color::WHITE);
 
         let first_visible_line = self.viewport.line_start;
         let first_visible_col = self.viewport.col_start;
@@ -773,57 +1234,72 @@ impl<'a> View<'a> {
 
         let mut current_col = 0;
 
-        let mut line_index = first_visible_line;
-        for line in self.buffer.borrow().lines().skip(first_visible_line).take(page_len + 1) {
-            let mut style = self
-                .styling
-                .as_ref()
-                .and_then(|s| s.result.get(line_index))
-                .map(|s| s.iter());
-            let mut idx = self.buffer.borrow().line_to_char(line_index);
+        let buffer = self.buffer.borrow();
+        let styles = self.styling.as_ref().map(|s| s.lines());
+        let last_visible_line = std::cmp::min(first_visible_line + page_len + 1, buffer.len_lines());
+        for line_index in first_visible_line..last_visible_line {
+            let mut style = styles.as_ref().and_then(|s| s.get(line_index)).map(|s| s.iter());
+            let mut idx = buffer.line_to_char(line_index);
 
-            for c in line.chars() {
+            for g in buffer.graphemes_on_line(line_index) {
                 let x = (current_col - first_visible_col as i32) as f32 * adv;
-
-                let fg = match style.as_mut().and_then(|s| s.next()) {
-                    None => Color::from_rgb(255, 255, 255),
-                    Some(s) => Color::from_rgb(s.foreground.r, s.foreground.g, s.foreground.b),
+                let len = g.chars().count();
+                let cells = grapheme_width(&g);
+
+                let selected = self.selection.map_or(false, |sel| sel.contains(idx));
+                let (fg, text_style) = match style.as_mut().and_then(|s| s.next()) {
+                    None => (Color::from_rgb(foreground.r, foreground.g, foreground.b), TextStyle::default()),
+                    Some(s) => {
+                        let font_style = s.font_style;
+                        // the background of the view is already drawn, and the selection is drawn over it
+                        let bg = s.background;
+                        let background = if selected || Some(bg) == theme.settings.background || bg.a == 0 {
+                            None
+                        } else {
+                            Some(Color::from_rgba(bg.r, bg.g, bg.b, bg.a))
+                        };
+                        let text_style = TextStyle {
+                            face: FontFace::new(
+                                font_style.contains(FontStyle::BOLD),
+                                font_style.contains(FontStyle::ITALIC),
+                            ),
+                            underline: font_style.contains(FontStyle::UNDERLINE),
+                            background,
+                        };
+                        (Color::from_rgb(s.foreground.r, s.foreground.g, s.foreground.b), text_style)
+                    }
                 };
-                match self.selection {
-                    Some(sel) if sel.contains(idx) => {
-                        let color = STYLE.theme.settings.selection.unwrap_or(highlighting::Color::WHITE);
-                        canvas.set_color(Color::from_rgb(color.r, color.g, color.b));
-                        canvas.move_to(x as _, y - canvas.font_metrics.descender - line_spacing);
-                        canvas.draw_rect(adv as _, line_spacing as _);
+                // the cluster is drawn with the style of its first char
+                if let Some(s) = style.as_mut() {
+                    for _ in 1..len {
+                        s.next();
                     }
-                    _ => (),
                 }
-                match c {
-                    '\t' => {
-                        let nbspace = ((current_col + tabsize) / tabsize) * tabsize;
-                        current_col = nbspace;
-                    }
-                    '\0' => (),
-                    '\r' => (), //idx -= 1,
-                    '\n' => (),
-                    // Bom hiding. TODO: rework
-                    '\u{feff}' | '\u{fffe}' => (),
+                if selected {
+                    let color = theme.settings.selection.unwrap_or(highlighting::Color::WHITE);
+                    canvas.set_color(Color::from_rgb(color.r, color.g, color.b));
+                    canvas.move_to(x as _, y - canvas.font_metrics.descender - line_spacing);
+                    canvas.draw_rect(adv * std::cmp::max(cells, 1) as f32, line_spacing as _);
+                }
+                match &*g {
+                    "\t" => current_col = next_col(current_col as usize, &g, tabsize) as i32,
+                    // line endings and lone zero width chars
+                    _ if cells == 0 => (),
                     _ => {
                         canvas.move_to(x as _, y as _);
                         canvas.set_color(fg);
-                        canvas.draw_char(c);
-                        current_col += 1;
+                        canvas.draw_grapheme(&g, text_style, cells);
+                        current_col += cells as i32;
                     }
                 }
-                idx += 1;
+                idx += len;
             }
-            line_index += 1;
             y += line_spacing;
             current_col = 0;
         }
 
         // Cursor
-        let fg = STYLE.theme.settings.caret.unwrap_or(highlighting::Color::WHITE);
+        let fg = theme.settings.caret.unwrap_or(highlighting::Color::WHITE);
         let (mut line, mut col) = (self.cursor.get_line(), self.cursor.get_col());
 
         if self.viewport.contain(line, col) {
@@ -865,7 +1341,7 @@ pub trait ViewCmd {
 #[cfg(test)]
 mod tests {
     use crate::buffer::Buffer;
-    use crate::view::View;
+    use crate::view::{Direction, Indentation, Selection, View};
     use crate::window::Geometry;
     use std::cell::RefCell;
     use std::rc::Rc;
@@ -1033,4 +1509,146 @@ mod tests {
         v.delete_at_cursor();
         assert_eq!(v.to_string(), "ell");
     }
+    #[test]
+    fn grapheme_width() {
+        use crate::cursor::grapheme_width;
+        assert_eq!(grapheme_width("a"), 1);
+        assert_eq!(grapheme_width("\u{4E2D}"), 2);
+        // combining marks
+        assert_eq!(grapheme_width("e\u{301}"), 1);
+        assert_eq!(grapheme_width("a\u{301}\u{302}"), 1);
+        // emoji ZWJ sequences and presentation selector
+        assert_eq!(grapheme_width("\u{1F469}\u{200D}\u{1F4BB}"), 2);
+        assert_eq!(grapheme_width("\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}"), 2);
+        assert_eq!(grapheme_width("\u{2764}\u{FE0F}"), 2);
+        assert_eq!(grapheme_width("\r\n"), 0);
+    }
+    #[test]
+    fn grapheme_clusters() {
+        // a combining accent, a wide char, an emoji sequence and a crlf
+        let b = Rc::new(RefCell::new(Buffer::from_str("e\u{301}\u{4E2D}\u{1F469}\u{200D}\u{1F4BB}x\r\nz")));
+        let mut v = View::new(b, GEO);
+        let mut positions = Vec::new();
+        for _ in 0..5 {
+            v.cursor_right();
+            positions.push((v.cursor.get_index(), v.line_idx(), v.col_idx()));
+        }
+        assert_eq!(positions, vec![(2, 0, 1), (3, 0, 3), (6, 0, 5), (7, 0, 6), (9, 1, 0)]);
+        v.cursor_left();
+        assert_eq!(v.cursor.get_index(), 7);
+
+        // a column in the middle of a wide char is the start of the char
+        v.set_cursor_point(0, 2);
+        assert_eq!(v.cursor.get_index(), 2);
+
+        v.cursor.set_index(6);
+        v.backspace();
+        assert_eq!(v.to_string(), "e\u{301}\u{4E2D}x\r\nz");
+        v.cursor.set_index(0);
+        v.delete_at_cursor();
+        assert_eq!(v.to_string(), "\u{4E2D}x\r\nz");
+    }
+    #[test]
+    fn indent_selection() {
+        let b = Rc::new(RefCell::new(Buffer::from_str("a\nb\nc")));
+        let mut v = View::new(b, GEO);
+        v.set_indentation(Indentation::Space(4));
+        v.selection = Some(Selection::new(0, 3));
+        v.indent();
+        assert_eq!(v.to_string(), "    a\n    b\nc");
+        v.undo();
+        assert_eq!(v.to_string(), "a\nb\nc");
+    }
+    #[test]
+    fn outdent() {
+        let b = Rc::new(RefCell::new(Buffer::from_str("      a\n\tb\nc")));
+        let mut v = View::new(b, GEO);
+        v.set_indentation(Indentation::Space(4));
+        v.selection = Some(Selection::new(0, 12));
+        v.outdent();
+        assert_eq!(v.to_string(), "  a\nb\nc");
+        v.clear_selection();
+        v.cursor.set_index(3);
+        v.outdent();
+        assert_eq!(v.to_string(), "a\nb\nc");
+        assert_eq!(v.cursor.get_index(), 1);
+    }
+    #[test]
+    fn convert_indentation() {
+        let b = Rc::new(RefCell::new(Buffer::from_str("\ta\n\t\tb\nc")));
+        let mut v = View::new(b, GEO);
+        v.set_indentation(Indentation::Tab(4));
+        v.convert_indentation_to_spaces(4);
+        assert_eq!(v.to_string(), "    a\n        b\nc");
+        v.convert_indentation_to_tabs(4);
+        assert_eq!(v.to_string(), "\ta\n\t\tb\nc");
+        v.undo();
+        assert_eq!(v.to_string(), "    a\n        b\nc");
+    }
+    #[test]
+    fn undo_to_saved_state() {
+        let b = Rc::new(RefCell::new(Buffer::from_str("hello")));
+        let mut v = View::new(b, GEO);
+        v.insert_char('a');
+        v.insert_char('b');
+        assert!(v.is_dirty());
+        v.undo();
+        assert!(v.is_dirty());
+        v.undo();
+        assert!(!v.is_dirty());
+        v.redo();
+        assert!(v.is_dirty());
+    }
+    #[test]
+    fn session() {
+        let b = Rc::new(RefCell::new(Buffer::from_str("")));
+        let mut v = View::new(b, GEO);
+        v.insert("hello\nworld");
+        v.cursor.set_index(3);
+        v.move_cursor(Direction::Right, true);
+        let session = v.session();
+        assert_eq!(session.content, Some("hello\nworld".to_owned()));
+
+        let b = Rc::new(RefCell::new(Buffer::new()));
+        let mut v = View::new(b, GEO);
+        v.restore_session(&session);
+        assert_eq!(v.to_string(), "hello\nworld");
+        assert_eq!(v.cursor.get_index(), 4);
+        assert_eq!(v.get_selection(), Some("l".to_owned()));
+    }
+    #[cfg(unix)]
+    #[test]
+    fn filter() {
+        let b = Rc::new(RefCell::new(Buffer::from_str("c\nb\na\n")));
+        let mut v = View::new(b, GEO);
+        v.filter("sort");
+        assert_eq!(v.to_string(), "a\nb\nc\n");
+        v.filter("exit 1");
+        assert_eq!(v.to_string(), "a\nb\nc\n");
+        assert!(v.get_message().is_some());
+        v.undo();
+        assert_eq!(v.to_string(), "c\nb\na\n");
+    }
+    #[test]
+    fn read_only() {
+        let b = Rc::new(RefCell::new(Buffer::from_str("text")));
+        let mut v = View::new(b, GEO);
+        v.insert_char('a');
+        v.set_read_only(true);
+        v.insert("b");
+        v.backspace();
+        v.delete_at_cursor();
+        v.indent();
+        v.undo();
+        assert_eq!(v.to_string(), "atext");
+        assert!(v.get_message().is_some());
+
+        // navigation and selection keep working
+        v.move_cursor(Direction::Right, true);
+        assert_eq!(v.get_selection(), Some("t".to_owned()));
+
+        v.set_read_only(false);
+        v.undo();
+        assert_eq!(v.to_string(), "text");
+    }
 }
@@ -1,18 +1,29 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::mpsc;
+use std::time::Instant;
 use std::{thread, time};
 
+use encoding::EncodingRef;
 use syntect::highlighting;
 
-use crate::buffer::Buffer;
+use crate::buffer::{encoding_from_label, is_large_file, Buffer, DiskState, LoadEvent};
+use crate::cli::{FileArg, Options};
 use crate::commands;
+use crate::diff;
 use crate::keybinding;
 use crate::keybinding::KeyBinding;
-use crate::nanovg::Canvas;
+use crate::nanovg::{Canvas, FontSource};
+use crate::instance;
+use crate::instance::{Client, Server};
+use crate::recovery::{Journal, RecoveryFile};
+use crate::session::{Session, ViewSession};
 use crate::view::{Direction, View};
 
+use crate::styling;
 use crate::styling::STYLE;
 
 #[derive(Debug, Clone, Copy)]
@@ -25,11 +36,78 @@ pub struct Geometry {
     pub font_advance: f32,
 }
 
+/// choice of the syntax picker going back to the detected syntax
+pub const AUTO_SYNTAX: &str = "Auto-detect";
+
+/// A question asked in the status bar, answered by typing a line of text
+/// or by picking one of the choices matching it
+struct Prompt {
+    message: String,
+    input: String,
+    validate: fn(&mut EditorWindow<'_>, &str),
+    // empty for a free text answer
+    choices: Vec<String>,
+    // indices of the choices containing the input
+    matches: Vec<usize>,
+    selected: usize,
+    // called with the selected choice when it changes, and with the initial one on Escape
+    preview: Option<(fn(&mut EditorWindow<'_>, &str), String)>,
+    // buffer of the view the prompt was opened on, which is current again when it is answered
+    buffer: Rc<RefCell<Buffer>>,
+}
+
+impl Prompt {
+    /// return the selected choice, if any
+    fn choice(&self) -> Option<&str> {
+        self.matches.get(self.selected).map(|&i| self.choices[i].as_str())
+    }
+
+    /// keep the choices containing the input, ignoring case
+    fn filter(&mut self) {
+        let input = self.input.to_lowercase();
+        let choices = &self.choices;
+        self.matches = (0..choices.len())
+            .filter(|&i| choices[i].to_lowercase().contains(&input))
+            .collect();
+        self.selected = 0;
+    }
+}
+
 pub struct EditorWindow<'v> {
     views: Vec<View<'v>>,
     buffers: Vec<Rc<RefCell<Buffer>>>,
     geometry: Geometry,
     current_view: usize,
+    prompt: Option<Prompt>,
+    closing: bool,
+    // the session is saved on exit, keeping the buffers without file
+    keep_session: bool,
+    // size of the font wanted by the zoom commands
+    font_size: f32,
+    // buffers modified on disk while having unsaved changes, waiting for the user decision
+    external_changes: Vec<Rc<RefCell<Buffer>>>,
+    journal: Journal,
+    // recovery files left by a previous run, waiting for the user decision
+    recovered: Vec<(PathBuf, RecoveryFile)>,
+    waiting: Vec<Waiter>,
+    loading: Vec<Loading>,
+}
+
+/// A large file loaded in the background
+struct Loading {
+    buffer: Rc<RefCell<Buffer>>,
+    events: mpsc::Receiver<LoadEvent>,
+    // 1-based line and column to go to once loaded
+    position: Option<(usize, usize)>,
+    // state of the view in the previous session, restored once loaded
+    session: Option<ViewSession>,
+}
+
+/// Files opened with --wait, the waiting process is notified once they are all closed
+struct Waiter {
+    buffers: Vec<Rc<RefCell<Buffer>>>,
+    // the other instance which asked for them, None when it is this one
+    client: Option<Client>,
 }
 
 pub trait WindowCmd {
@@ -39,14 +117,77 @@ pub trait WindowCmd {
     fn run(&mut self, _: &mut EditorWindow<'_>);
 }
 
-const FONT_SIZE: f32 = 16.0;
+/// return true if the session is restored on launch and saved on exit
+/// A window opened to edit a single file for another program doesn't touch the session.
+fn keep_session(options: &Options) -> bool {
+    super::SETTINGS.read().unwrap().get::<bool>("restoreSession").unwrap() && !options.wait
+}
+
+const MIN_FONT_SIZE: f32 = 6.0;
+const MAX_FONT_SIZE: f32 = 72.0;
+
+/// return the font size of the settings
+fn default_font_size() -> f32 {
+    super::SETTINGS.read().unwrap().get::<f32>("fontSize").unwrap()
+}
 
 impl<'v> EditorWindow<'v> {
-    pub fn new<P: AsRef<Path>>(geometry: Geometry, file: Option<P>) -> Self {
+    /// create the window, restoring the views of the session and opening the files of the command line
+    pub fn new(geometry: Geometry, session: Option<&Session>, options: &Options) -> Self {
         let mut w = EditorWindow::init(geometry);
-        w.add_new_view(file);
+        w.keep_session = keep_session(options);
+        if let Some(session) = session {
+            w.restore_session(session);
+        }
+        w.open(options, None);
+        if w.views.is_empty() {
+            w.add_new_view::<&Path>(None);
+        }
+        w.recovered = w.journal.pending();
+        if !w.recovered.is_empty() {
+            w.ask_recovery();
+        }
         w
     }
+
+    /// reopen the views saved in the session
+    /// Files that don't exist anymore are skipped.
+    fn restore_session(&mut self, session: &Session) {
+        let mut current_view = 0;
+        for (i, view_session) in session.views.iter().enumerate() {
+            match view_session.filename {
+                Some(ref filename) if !filename.exists() => continue,
+                Some(ref filename) => {
+                    if !self.add_new_view(Some(filename)) {
+                        continue;
+                    }
+                }
+                None => {
+                    self.add_new_view::<&Path>(None);
+                }
+            }
+            // the cursor of a large file is restored once its content is loaded
+            let b = self.current_buffer();
+            match self.loading.iter_mut().find(|l| Rc::ptr_eq(&l.buffer, &b)) {
+                Some(loading) => loading.session = Some(view_session.clone()),
+                None => self.get_current_view_mut().restore_session(view_session),
+            }
+            if i == session.current_view {
+                current_view = self.views.len() - 1;
+            }
+        }
+        self.current_view = current_view;
+    }
+
+    /// return the state of the window to save for the next launch
+    pub fn session(&self) -> Session {
+        Session {
+            width: self.geometry.w,
+            height: self.geometry.h,
+            current_view: self.current_view,
+            views: self.views.iter().map(|v| v.session()).collect(),
+        }
+    }
     fn init(geometry: Geometry) -> Self {
         let views = Vec::new();
         let buffers = Vec::new();
@@ -55,71 +196,857 @@ impl<'v> EditorWindow<'v> {
             buffers,
             geometry,
             current_view: 0,
+            prompt: None,
+            closing: false,
+            keep_session: false,
+            font_size: default_font_size(),
+            external_changes: Vec::new(),
+            journal: Journal::new(),
+            recovered: Vec::new(),
+            waiting: Vec::new(),
+            loading: Vec::new(),
         }
     }
 
     pub fn get_current_view(&self) -> &View<'_> {
         &self.views[self.current_view]
     }
-    pub fn get_current_view_mut(&mut self) -> &'v mut View<'_> {
+    pub fn get_current_view_mut(&mut self) -> &mut View<'v> {
         &mut self.views[self.current_view]
     }
 
-    pub fn add_new_view<P: AsRef<Path>>(&mut self, file: Option<P>) {
-        let b = match file {
-            None => Rc::new(RefCell::new(Buffer::new())),
-            Some(file) => Rc::new(RefCell::new(Buffer::from_file(file.as_ref()).expect("File not found"))),
+    /// ask the user for a line of text in the status bar
+    /// `validate` is called with the input when Return is pressed, nothing is done on Escape
+    pub fn prompt(&mut self, message: &str, default: &str, validate: fn(&mut EditorWindow<'_>, &str)) {
+        self.prompt = Some(Prompt {
+            message: message.to_owned(),
+            input: default.to_owned(),
+            validate,
+            choices: Vec::new(),
+            matches: Vec::new(),
+            selected: 0,
+            preview: None,
+            buffer: self.current_buffer(),
+        });
+    }
+
+    /// ask the user to pick one of the choices, filtered by the typed text
+    /// `preview` is called with the selected choice each time it changes, and with `current` on Escape.
+    pub fn pick(
+        &mut self,
+        message: &str,
+        choices: Vec<String>,
+        current: &str,
+        preview: fn(&mut EditorWindow<'_>, &str),
+        validate: fn(&mut EditorWindow<'_>, &str),
+    ) {
+        self.prompt = Some(Prompt {
+            message: message.to_owned(),
+            input: String::new(),
+            validate,
+            matches: (0..choices.len()).collect(),
+            selected: choices.iter().position(|c| c == current).unwrap_or(0),
+            choices,
+            preview: Some((preview, current.to_owned())),
+            buffer: self.current_buffer(),
+        });
+    }
+
+    /// call the preview of the picker with the selected choice
+    fn preview_choice(&mut self) {
+        let (preview, choice, buffer) = match self.prompt {
+            Some(ref prompt) => match (&prompt.preview, prompt.choice()) {
+                (Some((preview, _)), Some(choice)) => (*preview, choice.to_owned(), prompt.buffer.clone()),
+                _ => return,
+            },
+            None => return,
         };
+        if self.focus_buffer(&buffer) {
+            preview(self, &choice);
+        }
+    }
+
+    /// show a view of the buffer if the current one isn't on it
+    /// Files opened or loaded while a prompt is shown must not change what it acts upon.
+    /// Return false if no view shows the buffer anymore.
+    fn focus_buffer(&mut self, buffer: &Rc<RefCell<Buffer>>) -> bool {
+        if self.get_current_view().is_on_buffer(buffer) {
+            return true;
+        }
+        match self.views.iter().position(|v| v.is_on_buffer(buffer)) {
+            Some(i) => {
+                self.current_view = i;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// return true if the user is answering a prompt
+    pub fn is_prompting(&self) -> bool {
+        self.prompt.is_some()
+    }
+
+    fn prompt_char(&mut self, ch: char) {
+        if let Some(ref mut prompt) = self.prompt {
+            prompt.input.push(ch);
+            prompt.filter();
+        }
+        self.preview_choice();
+    }
+
+    fn prompt_key(&mut self, key: glutin::VirtualKeyCode) {
+        use glutin::VirtualKeyCode;
+        match key {
+            VirtualKeyCode::Back => {
+                if let Some(ref mut prompt) = self.prompt {
+                    prompt.input.pop();
+                    prompt.filter();
+                }
+                self.preview_choice();
+            }
+            VirtualKeyCode::Up | VirtualKeyCode::Down => {
+                if let Some(ref mut prompt) = self.prompt {
+                    let len = prompt.matches.len();
+                    if len == 0 {
+                        return;
+                    }
+                    prompt.selected = match key {
+                        VirtualKeyCode::Up => (prompt.selected + len - 1) % len,
+                        _ => (prompt.selected + 1) % len,
+                    };
+                }
+                self.preview_choice();
+            }
+            VirtualKeyCode::Escape => {
+                if let Some(Prompt {
+                    preview: Some((preview, initial)),
+                    buffer,
+                    ..
+                }) = self.prompt.take()
+                {
+                    if self.focus_buffer(&buffer) {
+                        preview(self, &initial);
+                    }
+                }
+            }
+            VirtualKeyCode::Return | VirtualKeyCode::NumpadEnter => {
+                if let Some(prompt) = self.prompt.take() {
+                    if !self.focus_buffer(&prompt.buffer) {
+                        return;
+                    }
+                    if prompt.choices.is_empty() {
+                        (prompt.validate)(self, &prompt.input);
+                    } else if let Some(choice) = prompt.choice() {
+                        (prompt.validate)(self, choice);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// use the given syntax for the current buffer, `AUTO_SYNTAX` to detect it again
+    pub fn set_syntax(&mut self, name: &str) {
+        let buffer = self.current_buffer();
+        let syntax = if name == AUTO_SYNTAX { None } else { Some(name.to_owned()) };
+        buffer.borrow_mut().set_syntax(syntax);
+        for view in self.views.iter_mut().filter(|v| v.is_on_buffer(&buffer)) {
+            view.detect_syntax();
+        }
+    }
+
+    /// use the given color theme in every view
+    pub fn set_theme(&mut self, name: &str) {
+        if !STYLE.write().unwrap().set_theme(name) {
+            self.get_current_view_mut().set_message(format!("Unknown theme: {}", name));
+            return;
+        }
+        for view in &mut self.views {
+            view.invalidate_styling_cache();
+        }
+    }
+
+    /// open a view on the given file, or on an empty buffer without file
+    /// Return false if the file couldn't be opened, the error is shown as message.
+    pub fn add_new_view<P: AsRef<Path>>(&mut self, file: Option<P>) -> bool {
+        match file {
+            None => {
+                self.add_new_view_with_buffer(Buffer::new());
+                true
+            }
+            Some(file) => self.open_path(file.as_ref(), None),
+        }
+    }
+
+    /// open a view on a file
+    /// A missing file gives an empty buffer created on save, a directory opens a file picker in it.
+    fn open_path(&mut self, path: &Path, encoding: Option<EncodingRef>) -> bool {
+        let buffer = match std::fs::metadata(path) {
+            Ok(ref metadata) if metadata.is_dir() => {
+                return match nfd::open_file_dialog(None, Some(&path.to_string_lossy())) {
+                    Ok(nfd::Response::Okay(file)) => self.open_path(Path::new(&file), encoding),
+                    _ => false,
+                };
+            }
+            Ok(_) if is_large_file(path) => {
+                let (buffer, events) = Buffer::load_large_file(path, encoding);
+                self.add_new_view_with_buffer(buffer);
+                self.loading.push(Loading {
+                    buffer: self.current_buffer(),
+                    events,
+                    position: None,
+                    session: None,
+                });
+                let message = format!("Loading {}...", path.display());
+                self.get_current_view_mut().set_message(message);
+                return true;
+            }
+            Ok(_) => Buffer::from_file_with_encoding(path, encoding),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Buffer::new_file(path)),
+            Err(e) => Err(e),
+        };
+        match buffer {
+            Ok(b) => {
+                let read_only = b.is_read_only();
+                self.add_new_view_with_buffer(b);
+                if read_only {
+                    let message = format!("{} is read-only", path.display());
+                    self.get_current_view_mut().set_message(message);
+                }
+                true
+            }
+            Err(e) => {
+                // the error needs a view to be shown on
+                if self.views.is_empty() {
+                    self.add_new_view_with_buffer(Buffer::new());
+                }
+                let message = format!("Can't open {}: {}", path.display(), e);
+                self.get_current_view_mut().set_message(message);
+                false
+            }
+        }
+    }
+
+    fn add_new_view_with_buffer(&mut self, buffer: Buffer) {
+        let b = Rc::new(RefCell::new(buffer));
         self.buffers.push(b.clone());
         let mut geometry = self.geometry;
-        //geometry.h -= 15; // footer TODO calculate it
+        geometry.h -= self.footer_height();
         let mut v = View::new(b.clone(), geometry);
         v.detect_syntax();
-        println!("{:?}", v.detect_indentation());
 
         let viewid = self.views.len();
         self.views.push(v);
         self.current_view = viewid;
     }
 
+    /// open the files of a command line
+    /// `client` is the other instance which sent it, if any.
+    pub fn open(&mut self, options: &Options, client: Option<Client>) {
+        let mut buffers = Vec::new();
+        for file in &options.files {
+            if self.open_file(file, options) {
+                buffers.push(self.current_buffer());
+            }
+        }
+        if options.stdin && self.open_stdin(options) {
+            buffers.push(self.current_buffer());
+        }
+        if options.wait && !buffers.is_empty() {
+            self.waiting.push(Waiter { buffers, client });
+        }
+    }
+
+    /// open a file given on the command line, or show it if it is already opened
+    fn open_file(&mut self, file: &FileArg, options: &Options) -> bool {
+        let opened = self
+            .buffers
+            .iter()
+            .find(|b| b.borrow().get_filename() == Some(file.path.as_path()))
+            .and_then(|b| self.views.iter().position(|v| v.is_on_buffer(b)));
+        match opened {
+            Some(i) => self.current_view = i,
+            None => {
+                let encoding = options.encoding.as_ref().and_then(|label| encoding_from_label(label));
+                if !self.open_path(&file.path, encoding) {
+                    return false;
+                }
+            }
+        }
+        if let Some(line) = file.line {
+            let position = (line, file.col.unwrap_or(1));
+            let b = self.current_buffer();
+            match self.loading.iter_mut().find(|l| Rc::ptr_eq(&l.buffer, &b)) {
+                Some(loading) => loading.position = Some(position),
+                None => self.get_current_view_mut().goto(position.0, position.1),
+            }
+        }
+        self.apply_options(options);
+        true
+    }
+
+    /// open a view on an unnamed buffer holding the standard input
+    fn open_stdin(&mut self, options: &Options) -> bool {
+        use std::io::Read;
+        let mut data = Vec::new();
+        if let Err(e) = std::io::stdin().read_to_end(&mut data) {
+            eprintln!("Can't read the standard input: {}", e);
+            return false;
+        }
+        let encoding = options.encoding.as_ref().and_then(|label| encoding_from_label(label));
+        self.add_new_view_with_buffer(Buffer::from_bytes(&data, encoding));
+        self.apply_options(options);
+        true
+    }
+
+    /// apply the command line options to the buffer of the current view
+    fn apply_options(&mut self, options: &Options) {
+        if options.readonly {
+            self.current_buffer().borrow_mut().set_read_only(true);
+        }
+    }
+
+    /// return the buffer of the current view
+    fn current_buffer(&self) -> Rc<RefCell<Buffer>> {
+        let view = self.get_current_view();
+        self.buffers.iter().find(|b| view.is_on_buffer(b)).unwrap().clone()
+    }
+
+    /// close the current view, asking what to do with the unsaved modifications of its buffer
+    pub fn request_close_view(&mut self) {
+        let b = self.current_buffer();
+        let shared = self.views.iter().filter(|v| v.is_on_buffer(&b)).count() > 1;
+        if shared || !b.borrow().is_dirty() {
+            self.close_view();
+            return;
+        }
+        let message = format!("Save changes to {}? [S]ave, [D]iscard or [C]ancel?", self.get_current_view().get_title());
+        self.prompt(&message, "", |w, answer| match answer.trim().to_lowercase().as_str() {
+            "s" | "save" => match w.get_current_view_mut().save() {
+                Ok(()) if !w.get_current_view().is_dirty() => w.close_view(),
+                Ok(()) => (),
+                Err(e) => w.get_current_view_mut().set_message(e.to_string()),
+            },
+            "d" | "discard" => w.close_view(),
+            _ => (),
+        });
+    }
+
+    /// close the current view, and its buffer if no other view shows it
+    fn close_view(&mut self) {
+        let b = self.current_buffer();
+        self.views.remove(self.current_view);
+        if !self.views.iter().any(|v| v.is_on_buffer(&b)) {
+            self.buffers.retain(|o| !Rc::ptr_eq(o, &b));
+            self.external_changes.retain(|o| !Rc::ptr_eq(o, &b));
+            // dropping the receiver stops the loading
+            self.loading.retain(|l| !Rc::ptr_eq(&l.buffer, &b));
+        }
+        self.notify_waiters();
+        if self.views.is_empty() {
+            self.add_new_view::<&Path>(None);
+        }
+        self.current_view = std::cmp::min(self.current_view, self.views.len() - 1);
+    }
+
+    /// notify the waiters whose files are all closed
+    /// The window is closed when it was opened with --wait itself.
+    fn notify_waiters(&mut self) {
+        let buffers = &self.buffers;
+        let (done, waiting): (Vec<Waiter>, Vec<Waiter>) = self
+            .waiting
+            .drain(..)
+            .partition(|w| !w.buffers.iter().any(|b| buffers.iter().any(|o| Rc::ptr_eq(o, b))));
+        self.waiting = waiting;
+        for waiter in done {
+            match waiter.client {
+                // dropping the client closes its connection
+                Some(client) => drop(client),
+                None => self.request_close(),
+            }
+        }
+    }
+
+    /// update the buffers of the files loaded in the background
+    /// Return true if something changed.
+    pub fn poll_loading(&mut self) -> bool {
+        let mut changed = false;
+        for loading in std::mem::replace(&mut self.loading, Vec::new()) {
+            let mut progress = None;
+            let result = loop {
+                match loading.events.try_recv() {
+                    Ok(LoadEvent::Progress(loaded, total)) => progress = Some(loaded * 100 / std::cmp::max(total, 1)),
+                    Ok(LoadEvent::Done(result)) => break Some(result),
+                    Err(mpsc::TryRecvError::Empty) => break None,
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        break Some(Err(io::Error::new(io::ErrorKind::Other, "Loading failed")))
+                    }
+                }
+            };
+            let buffer = &loading.buffer;
+            match result {
+                None => {
+                    if let Some(percent) = progress {
+                        for v in self.views.iter_mut().filter(|v| v.is_on_buffer(buffer)) {
+                            let message = format!("Loading {}... {}%", v.get_title(), percent);
+                            v.set_message(message);
+                        }
+                        changed = true;
+                    }
+                    self.loading.push(loading);
+                    continue;
+                }
+                Some(Ok(file)) => {
+                    buffer.borrow_mut().finish_loading(file);
+                    for v in self.views.iter_mut().filter(|v| v.is_on_buffer(buffer)) {
+                        v.refresh();
+                        if let Some(ref session) = loading.session {
+                            v.restore_session(session);
+                        }
+                        if let Some((line, col)) = loading.position {
+                            v.goto(line, col);
+                        }
+                        let message = format!("{} opened in large-file mode, highlighting is disabled", v.get_title());
+                        v.set_message(message);
+                    }
+                }
+                Some(Err(e)) => {
+                    buffer.borrow_mut().abort_loading();
+                    for v in self.views.iter_mut().filter(|v| v.is_on_buffer(buffer)) {
+                        let message = format!("Can't open {}: {}", v.get_title(), e);
+                        v.set_message(message);
+                    }
+                }
+            }
+            changed = true;
+        }
+        changed
+    }
+
+    /// send the edited texts to the highlighting threads, and collect the lines they highlighted
+    /// Return true if visible lines of the current view were highlighted.
+    pub fn highlight(&mut self) -> bool {
+        let visible = self.views[self.current_view].highlight();
+        for v in self.views.iter_mut() {
+            v.highlight();
+        }
+        visible
+    }
+
+    /// open a new view on an unnamed buffer holding the given text
+    pub fn add_new_view_with_text(&mut self, text: &str) {
+        self.add_new_view::<&Path>(None);
+        let view = self.get_current_view_mut();
+        view.insert(text);
+        view.move_to_start();
+    }
+
+    /// show the next view
+    pub fn next_view(&mut self) {
+        self.current_view = (self.current_view + 1) % self.views.len();
+    }
+
+    /// show the previous view
+    pub fn previous_view(&mut self) {
+        self.current_view = (self.current_view + self.views.len() - 1) % self.views.len();
+    }
+
+    /// look for files modified by other programs
+    /// Unmodified buffers are reloaded, the user is asked what to do for the others.
+    /// Return true if something changed.
+    pub fn check_external_changes(&mut self) -> bool {
+        let mut changed = false;
+        for b in self.buffers.clone() {
+            let state = b.borrow_mut().check_disk();
+            match state {
+                DiskState::Unchanged => (),
+                DiskState::Deleted => {
+                    b.borrow_mut().forget_disk();
+                    for v in self.views.iter_mut().filter(|v| v.is_on_buffer(&b)) {
+                        let message = format!("{} was deleted on disk", v.get_title());
+                        v.set_message(message);
+                    }
+                    changed = true;
+                }
+                // large files are not read again without asking
+                DiskState::Modified if !b.borrow().is_dirty() && !b.borrow().is_large() => {
+                    self.reload_buffer(&b);
+                    changed = true;
+                }
+                DiskState::Modified => {
+                    if !self.external_changes.iter().any(|c| Rc::ptr_eq(c, &b)) {
+                        self.external_changes.push(b.clone());
+                    }
+                }
+            }
+        }
+        if !self.external_changes.is_empty() && !self.is_prompting() {
+            self.ask_external_change();
+            changed = true;
+        }
+        changed
+    }
+
+    /// reload the buffer in every view showing it
+    /// Large files are read again in the background.
+    fn reload_buffer(&mut self, buffer: &Rc<RefCell<Buffer>>) {
+        if buffer.borrow().is_large() {
+            let result = buffer.borrow_mut().reload_in_background();
+            for v in self.views.iter_mut().filter(|v| v.is_on_buffer(buffer)) {
+                let message = match &result {
+                    Ok(_) => format!("Reloading {}...", v.get_title()),
+                    Err(e) => e.to_string(),
+                };
+                v.set_message(message);
+            }
+            if let Ok(events) = result {
+                self.loading.push(Loading {
+                    buffer: buffer.clone(),
+                    events,
+                    position: None,
+                    session: None,
+                });
+            }
+            return;
+        }
+        let mut reloaded = false;
+        for v in self.views.iter_mut().filter(|v| v.is_on_buffer(buffer)) {
+            if reloaded {
+                v.refresh();
+                continue;
+            }
+            match v.reload() {
+                Ok(()) => {
+                    let message = format!("{} reloaded", v.get_title());
+                    v.set_message(message);
+                }
+                Err(e) => v.set_message(e.to_string()),
+            }
+            reloaded = true;
+        }
+    }
+
+    /// ask what to do with the first buffer modified both on disk and in the editor
+    fn ask_external_change(&mut self) {
+        let b = self.external_changes[0].clone();
+        let title = match self.views.iter().find(|v| v.is_on_buffer(&b)) {
+            Some(v) => v.get_title(),
+            None => return,
+        };
+        // reading and comparing a large file would block the window
+        let message = if b.borrow().is_large() {
+            format!("{} changed on disk. [R]eload or [K]eep mine?", title)
+        } else {
+            let disk = b.borrow().read_disk().unwrap_or_default();
+            let mine = b.borrow().to_string();
+            let (added, removed) = diff::count_changes(&diff::diff_lines(&mine, &disk));
+            format!(
+                "{} changed on disk (+{} -{} lines). [R]eload, [K]eep mine or show [D]iff?",
+                title, added, removed
+            )
+        };
+        self.prompt(&message, "", |w, answer| {
+            let b = w.external_changes.remove(0);
+            let large = b.borrow().is_large();
+            match answer.trim().to_lowercase().as_str() {
+                "r" | "reload" => w.reload_buffer(&b),
+                "d" | "diff" if !large => {
+                    w.show_disk_diff(&b);
+                    w.external_changes.insert(0, b);
+                }
+                _ => b.borrow_mut().accept_disk(),
+            }
+            if !w.external_changes.is_empty() {
+                w.ask_external_change();
+            }
+        });
+    }
+
+    /// open a view with the differences between the buffer and the file on disk
+    fn show_disk_diff(&mut self, buffer: &Rc<RefCell<Buffer>>) {
+        let title = match self.views.iter().find(|v| v.is_on_buffer(buffer)) {
+            Some(v) => v.get_title(),
+            None => return,
+        };
+        let disk = buffer.borrow().read_disk().unwrap_or_default();
+        let mine = buffer.borrow().to_string();
+        let text = format!(
+            "--- {} (unsaved)\n+++ {} (on disk)\n{}",
+            title,
+            title,
+            diff::unified(&diff::diff_lines(&mine, &disk), 3)
+        );
+        self.add_new_view_with_text(&text);
+    }
+
+    /// write a recovery copy of the modified buffers
+    pub fn autosave(&mut self) {
+        if let Err(e) = self.journal.autosave(&self.buffers) {
+            self.get_current_view_mut().set_message(format!("Autosave failed: {}", e));
+        }
+    }
+
+    /// ask what to do with the first recovery file left by a previous run
+    fn ask_recovery(&mut self) {
+        let title = match self.recovered[0].1.filename {
+            Some(ref filename) => filename.display().to_string(),
+            None => "untitled".to_owned(),
+        };
+        let message = format!("Unsaved changes of {} were recovered. [R]estore or [D]iscard?", title);
+        self.prompt(&message, "", |w, answer| {
+            let (path, file) = w.recovered.remove(0);
+            match answer.trim().to_lowercase().as_str() {
+                "r" | "restore" => {
+                    w.restore_recovery(&file);
+                    let _ = std::fs::remove_file(path);
+                }
+                "d" | "discard" => {
+                    let _ = std::fs::remove_file(path);
+                }
+                // keep it for the next launch
+                _ => (),
+            }
+            if !w.recovered.is_empty() {
+                w.ask_recovery();
+            }
+        });
+    }
+
+    /// open a view with the content of a recovery file
+    fn restore_recovery(&mut self, file: &RecoveryFile) {
+        match file.filename {
+            Some(ref filename) => {
+                let view = self.buffers.iter().find(|b| b.borrow().get_filename() == Some(filename.as_path()));
+                match view.and_then(|b| self.views.iter().position(|v| v.is_on_buffer(b))) {
+                    Some(i) => self.current_view = i,
+                    None => {
+                        if !self.add_new_view(Some(filename)) {
+                            return;
+                        }
+                    }
+                }
+            }
+            None => {
+                // reuse the empty view opened at startup
+                let empty = {
+                    let b = self.buffers.iter().find(|b| self.get_current_view().is_on_buffer(b));
+                    b.map_or(false, |b| {
+                        let b = b.borrow();
+                        b.get_filename().is_none() && !b.is_dirty() && b.len_chars() == 0
+                    })
+                };
+                if !empty {
+                    self.add_new_view::<&Path>(None);
+                }
+            }
+        }
+        self.get_current_view_mut().replace_content(&file.content);
+    }
+
     fn resize(&mut self, width: f32, height: f32) {
         self.geometry.w = width;
         self.geometry.h = height;
         let mut geometry = self.geometry;
-        //geometry.h -= 15; // footer TODO calculate it
+        geometry.h -= self.footer_height();
         for i in 0..self.views.len() {
             self.views[i].relayout(geometry);
         }
     }
-    fn draw(&mut self, canvas: &mut Canvas) {
-        // screen.set_font("gui");
-
-        // let footer_height = screen.get_font_metrics("gui").line_spacing;
-        // let fg = STYLE.theme.settings.foreground.unwrap_or(highlighting::Color::BLACK);
-        // let bg = STYLE.theme.settings.background.unwrap_or(highlighting::Color::WHITE);
-        // screen.set_color(Color::RGB(fg.r, fg.g, fg.b));
-        // screen.move_to(0, self.geometry.h as i32 - footer_height);
-        // screen.draw_rect(self.geometry.w as _, footer_height as _);
-        // screen.set_color(Color::RGB(bg.r, bg.g, bg.b));
-
-        // let (line, col) = self.get_current_view().cursor_as_point();
-        // screen.draw_str(&format!(
-        //     "({},{})    {}    {}",
-        //     line,
-        //     col,
-        //     self.get_current_view().get_syntax(),
-        //     self.get_current_view().get_encoding()
-        // ));
 
+    /// return the titles of the views whose buffer has unsaved modifications
+    /// Buffers without file are kept in the session when it is restored, so they don't count.
+    pub fn dirty_views(&self) -> Vec<String> {
+        self.buffers
+            .iter()
+            .filter(|b| b.borrow().is_dirty())
+            .filter(|b| !self.keep_session || b.borrow().get_filename().is_some())
+            .filter_map(|b| self.views.iter().find(|v| v.is_on_buffer(b)))
+            .map(|v| v.get_title())
+            .collect()
+    }
+
+    /// save every modified buffer
+    /// Stop and report the error on the current view at the first failure
+    pub fn save_all(&mut self) -> bool {
+        for i in 0..self.views.len() {
+            if self.views[i].is_dirty() {
+                if let Err(e) = self.views[i].save() {
+                    let title = self.views[i].get_title();
+                    self.get_current_view_mut().set_message(format!("{}: {}", title, e));
+                    return false;
+                }
+            }
+        }
+        // the save dialog could have been cancelled
+        self.dirty_views().is_empty()
+    }
+
+    /// close the window, asking what to do with unsaved modifications first
+    pub fn request_close(&mut self) {
+        let dirty = self.dirty_views();
+        if dirty.is_empty() {
+            self.closing = true;
+            return;
+        }
+        let message = format!("Unsaved changes in {}. [S]ave all, [D]iscard or [C]ancel?", dirty.join(", "));
+        self.prompt(&message, "", |w, answer| match answer.trim().to_lowercase().as_str() {
+            "s" | "save" => {
+                if w.save_all() {
+                    w.closing = true;
+                }
+            }
+            "d" | "discard" => w.closing = true,
+            _ => (),
+        });
+    }
+
+    /// return the font size to draw with
+    pub fn get_font_size(&self) -> f32 {
+        self.font_size
+    }
+
+    /// make the font bigger, or smaller with a negative step
+    pub fn zoom(&mut self, step: f32) {
+        self.font_size = (self.font_size + step).max(MIN_FONT_SIZE).min(MAX_FONT_SIZE);
+    }
+
+    /// go back to the font size of the settings
+    pub fn reset_zoom(&mut self) {
+        self.font_size = default_font_size();
+    }
+
+    /// lay the views out again with the metrics of a new font size
+    pub fn set_font_metrics(&mut self, font_height: f32, font_advance: f32) {
+        self.geometry.font_height = font_height;
+        self.geometry.font_advance = font_advance;
+        let (width, height) = (self.geometry.w, self.geometry.h);
+        self.resize(width, height);
+    }
+
+    /// return true once the window can be closed
+    pub fn should_close(&self) -> bool {
+        self.closing
+    }
+
+    fn footer_height(&self) -> f32 {
+        self.geometry.font_height
+    }
+
+    fn draw(&mut self, canvas: &mut Canvas) {
         self.get_current_view().draw(canvas);
+
+        // status bar
+        let footer_height = self.footer_height();
+        let theme = styling::theme();
+        let fg = theme.settings.foreground.unwrap_or(highlighting::Color::WHITE);
+        let bg = theme.settings.background.unwrap_or(highlighting::Color::BLACK);
+        canvas.set_color(nanovg::Color::from_rgb(fg.r, fg.g, fg.b));
+        canvas.move_to(0.0, self.geometry.h - footer_height);
+        canvas.draw_rect(self.geometry.w, footer_height);
+        canvas.set_color(nanovg::Color::from_rgb(bg.r, bg.g, bg.b));
+        canvas.move_to(0.0, self.geometry.h + canvas.font_metrics.descender);
+
+        if let Some(ref prompt) = self.prompt {
+            canvas.draw_str(&format!("{} {}_", prompt.message, prompt.input));
+            self.draw_choices(canvas, prompt);
+            return;
+        }
+
+        let view = self.get_current_view();
+        if let Some(message) = view.get_message() {
+            canvas.draw_str(&format!("{}    ", message));
+        }
+        canvas.draw_str(&format!(
+            "{}{}{}    Ln {}, Col {}    {}    {}    {}    {}",
+            view.get_title(),
+            if view.is_dirty() { "*" } else { "" },
+            if view.is_read_only() { " [read-only]" } else { "" },
+            view.line_idx() + 1,
+            view.col_idx() + 1,
+            view.get_syntax(),
+            view.get_indentation(),
+            if view.is_linefeed_mixed() {
+                format!("{} (mixed)", view.get_linefeed())
+            } else {
+                view.get_linefeed().to_string()
+            },
+            if view.has_bom() {
+                format!("{} with BOM", view.get_encoding())
+            } else {
+                view.get_encoding().to_owned()
+            }
+        ));
+    }
+
+    /// draw the choices of a picker above the status bar, the selected one highlighted
+    fn draw_choices(&self, canvas: &mut Canvas, prompt: &Prompt) {
+        const MAX_CHOICES: usize = 10;
+        let theme = styling::theme();
+        let fg = theme.settings.foreground.unwrap_or(highlighting::Color::WHITE);
+        let bg = theme.settings.background.unwrap_or(highlighting::Color::BLACK);
+        let selection = theme.settings.selection.unwrap_or(fg);
+
+        let line_height = self.geometry.font_height;
+        let first = (prompt.selected + 1).saturating_sub(MAX_CHOICES);
+        let shown = &prompt.matches[first..std::cmp::min(first + MAX_CHOICES, prompt.matches.len())];
+        let top = self.geometry.h - self.footer_height() - shown.len() as f32 * line_height;
+        for (row, &i) in shown.iter().enumerate() {
+            let y = top + row as f32 * line_height;
+            let color = if first + row == prompt.selected { selection } else { bg };
+            canvas.set_color(nanovg::Color::from_rgb(color.r, color.g, color.b));
+            canvas.move_to(0.0, y);
+            canvas.draw_rect(self.geometry.w, line_height);
+            canvas.set_color(nanovg::Color::from_rgb(fg.r, fg.g, fg.b));
+            canvas.move_to(0.0, y + line_height + canvas.font_metrics.descender);
+            canvas.draw_str(&prompt.choices[i]);
+        }
     }
 }
 
-pub fn start<P: AsRef<Path>>(file: Option<P>) {
+pub fn start(options: Options) {
+    // hand the files to the running instance, unless a new window is wanted
+    let single_instance = super::SETTINGS.read().unwrap().get::<bool>("singleInstance").unwrap();
+    let new_window = options.new_window || options.stdin;
+    if single_instance && !new_window {
+        match instance::forward(&options) {
+            Ok(true) => return,
+            Ok(false) => (),
+            Err(e) => eprintln!("Can't reach the running instance: {}", e),
+        }
+    }
+    let server = if single_instance && !new_window && !options.wait {
+        match Server::bind() {
+            Ok(server) => Some(server),
+            Err(e) => {
+                eprintln!("Can't listen for other instances: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
     let mut width = super::SETTINGS.read().unwrap().get::<f32>("width").unwrap();
     let mut height = super::SETTINGS.read().unwrap().get::<f32>("height").unwrap();
 
-    let mut system_window = crate::nanovg::System::new("None", width, height, FONT_SIZE);
+    let restore_session = keep_session(&options);
+    let session = if restore_session { Session::load() } else { None };
+    if let Some(ref session) = session {
+        width = session.width;
+        height = session.height;
+    }
+
+    let font = {
+        let settings = super::SETTINGS.read().unwrap();
+        match settings.get::<String>("fontPath").unwrap() {
+            ref path if path.is_empty() => FontSource::Bundled(settings.get::<String>("fontFamily").unwrap()),
+            path => FontSource::File(PathBuf::from(path)),
+        }
+    };
+    let fallbacks = super::SETTINGS.read().unwrap().get::<Vec<PathBuf>>("fallbackFonts").unwrap();
+    let mut system_window =
+        crate::nanovg::System::new("None", width, height, &font, default_font_size(), &fallbacks);
 
     // create window. TODO: passing font_height as parameter feel off
     let font_height = system_window.canvas.font_metrics.line_height;
@@ -133,7 +1060,8 @@ pub fn start<P: AsRef<Path>>(file: Option<P>) {
             font_height: font_height,
             font_advance: font_advance,
         },
-        file,
+        session.as_ref(),
+        &options,
     );
 
     // create view and windows cmd binding
@@ -159,21 +1087,30 @@ pub fn start<P: AsRef<Path>>(file: Option<P>) {
         DoubleClicked,
         Released,
     }
-    use std::time::{Duration, Instant};
+    use std::time::Duration;
     let mut redraw = true;
-    let mut running = true;
     let mut mousex = 0.0;
     let mut mousey = 0.0;
     let mut mouse_state = MouseState::Released;
     let mut last_click_instant = Instant::now();
-    while running {
+    let mut last_disk_check = Instant::now();
+    let mut last_autosave = Instant::now();
+    let autosave_interval = super::SETTINGS.read().unwrap().get::<u64>("autosaveInterval").unwrap();
+    while !win.should_close() {
         let mut resized: Option<glutin::dpi::LogicalSize> = None;
         system_window.events_loop.poll_events(|event| {
             use glutin::{dpi::LogicalPosition, ElementState, Event, MouseButton, MouseScrollDelta, WindowEvent::*};
 
             if let Event::WindowEvent { event, .. } = event {
                 match event {
-                    CloseRequested => running = false,
+                    CloseRequested => {
+                        win.request_close();
+                        redraw = true;
+                    }
+                    Focused(true) => {
+                        redraw |= win.check_external_changes();
+                        last_disk_check = Instant::now();
+                    }
                     Resized(size) => {
                         resized = Some(size);
                     }
@@ -181,6 +1118,10 @@ pub fn start<P: AsRef<Path>>(file: Option<P>) {
                         0x00...0x1F => (),
                         0x80...0x9F => (),
                         0x7F => (),
+                        _ if win.is_prompting() => {
+                            win.prompt_char(ch);
+                            redraw = true;
+                        }
                         _ => {
                             win.views[win.current_view].insert_char(ch);
                             redraw = true;
@@ -189,6 +1130,12 @@ pub fn start<P: AsRef<Path>>(file: Option<P>) {
                     KeyboardInput { input, .. } => {
                         if input.state == glutin::ElementState::Pressed {
                             if let Some(k) = input.virtual_keycode {
+                                if win.is_prompting() {
+                                    win.prompt_key(k);
+                                    redraw = true;
+                                    return;
+                                }
+                                win.views[win.current_view].clear_message();
                                 let mut km = keybinding::Mod::NONE;
                                 if input.modifiers.ctrl {
                                     km |= keybinding::Mod::CTRL
@@ -274,10 +1221,38 @@ pub fn start<P: AsRef<Path>>(file: Option<P>) {
             redraw = true;
         }
 
+        if last_disk_check.elapsed() > Duration::from_secs(1) {
+            redraw |= win.check_external_changes();
+            last_disk_check = Instant::now();
+        }
+
+        if win.get_font_size() != system_window.font_size() {
+            system_window.set_font_size(win.get_font_size());
+            let metrics = &system_window.canvas.font_metrics;
+            win.set_font_metrics(metrics.line_height, metrics.advance);
+            redraw = true;
+        }
+
+        redraw |= win.poll_loading();
+        // the lines are highlighted by a thread per view, drawn as they come
+        redraw |= win.highlight();
+
+        if let Some(ref server) = server {
+            while let Some((options, client)) = server.accept() {
+                win.open(&options, client);
+                redraw = true;
+            }
+        }
+
+        if autosave_interval > 0 && last_autosave.elapsed() > Duration::from_secs(autosave_interval) {
+            win.autosave();
+            last_autosave = Instant::now();
+        }
+
         // redraw only when needed
         if redraw {
             // clear
-            let bg = STYLE.theme.settings.background.unwrap_or(highlighting::Color::BLACK);
+            let bg = styling::theme().settings.background.unwrap_or(highlighting::Color::BLACK);
 
             system_window.canvas.clear(nanovg::Color::from_rgb(bg.r, bg.g, bg.b));
             win.draw(&mut system_window.canvas);
@@ -292,4 +1267,11 @@ pub fn start<P: AsRef<Path>>(file: Option<P>) {
 
     super::SETTINGS.write().unwrap().set("width", width as i64).unwrap();
     super::SETTINGS.write().unwrap().set("height", height as i64).unwrap();
+
+    if restore_session {
+        if let Err(e) = win.session().save() {
+            eprintln!("Can't save the session: {}", e);
+        }
+    }
+    win.journal.clear();
 }